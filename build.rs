@@ -32,14 +32,19 @@ fn main() {
                 | OutFlags::PixIndependent
                 | OutFlags::DeepColorAware
                 | OutFlags::SendUpdateParamsUI
-                | OutFlags::NonParamVary,
+                | OutFlags::NonParamVary
+                | OutFlags::WideTimeInput
+                | OutFlags::IUseShutterAngle,
         ),
         Property::AE_Effect_Global_OutFlags_2(
             OutFlags2::FloatColorAware
                 | OutFlags2::SupportsSmartRender
                 | OutFlags2::SupportsThreadedRendering
                 | OutFlags2::SupportsGetFlattenedSequenceData
-                | OutFlags2::ParamGroupStartCollapsedFlag,
+                | OutFlags2::SupportsQueryDynamicFlags
+                | OutFlags2::ParamGroupStartCollapsedFlag
+                | OutFlags2::IUse3DCamera
+                | OutFlags2::IUse3DLights,
         ),
         Property::AE_Effect_Match_Name("TweakShader"),
         Property::AE_Reserved_Info(0),
@@ -0,0 +1,1186 @@
+// Plugin specific pragmas. tweak_shader knows nothing about these, so they are
+// parsed and stripped from the source before the render context sees it.
+// Every line is kept, even if emptied, so error line numbers still match.
+
+use std::collections::HashMap;
+
+// Fields of `#pragma input(...)` that belong to the plugin
+const INPUT_KEYS: &[&str] = &[
+    "fallback",
+    "time_offset",
+    "units",
+    "ui",
+    "precision",
+    "space",
+    "label",
+    "group",
+    "order",
+    "visible_if",
+    "enabled_if",
+];
+
+#[derive(Debug, Default, Clone)]
+pub struct Annotations {
+    pub expansion: Option<Expansion>,
+    pub simulation: Option<Simulation>,
+    // #pragma time_dependent(true|false) overrides the
+    // analysis that decides if frames can be cached.
+    pub time_dependent: Option<bool>,
+    // #pragma y_up(true) flips point inputs so y grows upwards
+    pub y_up: bool,
+    // textures written by persistent passes, targets and relays.
+    // these pragmas are left in place for tweak_shader.
+    pub persistent_targets: Vec<String>,
+    // true if the shader declares any pass, target or relay
+    pub has_passes: bool,
+    // keyed by input name
+    pub inputs: HashMap<String, InputAnnotations>,
+    // vec3, vec4 and point3d inputs in declaration order. tweak_shader
+    // sees their fields as raw bytes, the plugin fills them from params.
+    pub vectors: Vec<VectorInput>,
+}
+
+impl Annotations {
+    pub fn input(&self, name: &str) -> Option<&InputAnnotations> {
+        self.inputs.get(name)
+    }
+
+    pub fn vector(&self, name: &str) -> Option<&VectorInput> {
+        self.vectors.iter().find(|v| v.name == name)
+    }
+
+    // True if any input is sampled away from the current time, simulations
+    // check out the params of every frame they pre roll.
+    pub fn reads_other_times(&self) -> bool {
+        self.simulation.is_some() || self.inputs.values().any(|i| i.time_offset.is_some())
+    }
+}
+
+// Plugin specific fields of a single input pragma
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InputAnnotations {
+    pub fallback: Option<Fallback>,
+    pub time_offset: Option<TimeOffset>,
+    pub units: Option<Units>,
+    pub ui: Option<FloatUi>,
+    // decimal places the slider shows
+    pub precision: Option<i16>,
+    pub space: Option<PointSpace>,
+    // the `min` and `max` of point inputs, which tweak_shader
+    // does not enforce. kept in the pragma for tweak_shader.
+    pub point_min: Option<[f32; 2]>,
+    pub point_max: Option<[f32; 2]>,
+    // shown in place of the uniform's name
+    pub label: Option<String>,
+    // prefixed to the name, inputs are only grouped within their type
+    pub group: Option<String>,
+    // inputs with an order come first, lowest first
+    pub order: Option<i32>,
+    // the param is never shown, the input keeps its default
+    pub hidden: bool,
+    // shown only while the condition holds
+    pub visible_if: Option<Condition>,
+    // grayed out unless the condition holds
+    pub enabled_if: Option<Condition>,
+}
+
+impl InputAnnotations {
+    // The conditions on other inputs this input has
+    pub fn conditions(&self) -> impl Iterator<Item = &Condition> {
+        self.visible_if.iter().chain(&self.enabled_if)
+    }
+}
+
+// Extracts the plugin pragmas from `src`, returning the
+// source that should be handed to tweak_shader.
+pub fn extract(src: &str) -> Result<(String, Annotations), String> {
+    let mut annotations = Annotations::default();
+    let mut stripped = String::with_capacity(src.len());
+
+    for line in src.lines() {
+        match parse_pragma(line) {
+            Some(("expand", args)) => {
+                annotations.expansion = Some(Expansion::parse(&args)?);
+            }
+            Some(("simulation", args)) => {
+                annotations.simulation = Some(Simulation::parse(&args)?);
+            }
+            Some(("time_dependent", args)) => {
+                annotations.time_dependent = Some(parse_flag("time_dependent", &args)?);
+            }
+            Some(("y_up", args)) => {
+                annotations.y_up = parse_flag("y_up", &args)?;
+            }
+            Some(("pass" | "target" | "relay", args)) => {
+                annotations.has_passes = true;
+
+                if args
+                    .iter()
+                    .any(|a| a.key.is_none() && a.value == "persistent")
+                {
+                    annotations.persistent_targets.extend(
+                        args.iter()
+                            .filter(|a| matches!(a.key, Some("name" | "target")))
+                            .map(|a| a.value.to_owned()),
+                    );
+                }
+                stripped.push_str(line);
+            }
+            // tweak_shader has no vector inputs, the pragma is removed entirely
+            Some(("input", args)) if VectorKind::declared_by(&args).is_some() => {
+                let vector = VectorInput::parse(&args, line)?;
+                if args.iter().any(|a| a.is_plugin_input_key()) {
+                    let input = InputAnnotations::parse(&args)?;
+                    annotations.inputs.insert(vector.name.clone(), input);
+                }
+                annotations.vectors.push(vector);
+            }
+            Some(("input", args))
+                if args.iter().any(|a| a.is_plugin_input_key()) || is_point(&args) =>
+            {
+                let name = args
+                    .iter()
+                    .find(|a| a.key == Some("name"))
+                    .map(|a| a.value.to_owned())
+                    .ok_or_else(|| format!("input pragma has no name: {line}"))?;
+
+                let input = InputAnnotations::parse(&args)?;
+                annotations.inputs.insert(name, input);
+
+                let rest: Vec<&str> = args
+                    .iter()
+                    .filter(|a| !a.is_plugin_input_key())
+                    .map(|a| a.raw.trim())
+                    .collect();
+
+                stripped.push_str(&format!("#pragma input({})", rest.join(", ")));
+            }
+            _ => stripped.push_str(line),
+        }
+        stripped.push('\n');
+    }
+
+    Ok((stripped, annotations))
+}
+
+impl InputAnnotations {
+    fn parse(args: &[PragmaArg]) -> Result<Self, String> {
+        let mut out = InputAnnotations::default();
+
+        for arg in args.iter().filter(|a| a.is_plugin_input_key()) {
+            match arg.key {
+                Some("visible_if") => out.visible_if = Some(Condition::parse(arg.value)?),
+                Some("enabled_if") => out.enabled_if = Some(Condition::parse(arg.value)?),
+                Some("label") => out.label = Some(arg.value.to_owned()),
+                Some("group") => out.group = Some(arg.value.to_owned()),
+                Some("order") => {
+                    out.order = Some(
+                        arg.value
+                            .parse()
+                            .map_err(|_| format!("invalid order \"{}\"", arg.value))?,
+                    )
+                }
+                None => out.hidden = true,
+                Some("space") => out.space = Some(PointSpace::parse(arg.value)?),
+                Some("fallback") => out.fallback = Some(Fallback::parse(arg.value)?),
+                Some("time_offset") => out.time_offset = TimeOffset::parse(arg.value)?,
+                Some("units") => out.units = Some(Units::parse(arg.value)?),
+                Some("ui") => out.ui = Some(FloatUi::parse(arg.value)?),
+                Some("precision") => {
+                    out.precision = Some(
+                        arg.value
+                            .parse::<i16>()
+                            .ok()
+                            .filter(|p| (0..=6).contains(p))
+                            .ok_or_else(|| format!("invalid precision \"{}\"", arg.value))?,
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        if is_point(args) {
+            for arg in args {
+                let bound = || {
+                    parse_list(arg.value, 2)
+                        .map(|l| [l[0], l[1]])
+                        .ok_or_else(|| format!("invalid point bound \"{}\"", arg.value))
+                };
+                match arg.key {
+                    Some("min") => out.point_min = Some(bound()?),
+                    Some("max") => out.point_max = Some(bound()?),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    // Whether the value is in layer pixels, `ui=pixels` sliders show pixels
+    // so they are scaled like `units=pixels`.
+    pub fn in_pixels(&self) -> bool {
+        self.units == Some(Units::Pixels) || self.ui == Some(FloatUi::Pixels)
+    }
+
+    // Clamps a point to the pragma's min and max
+    pub fn clamp_point(&self, mut point: [f32; 2]) -> [f32; 2] {
+        for (i, component) in point.iter_mut().enumerate() {
+            if let Some(min) = self.point_min {
+                *component = component.max(min[i]);
+            }
+            if let Some(max) = self.point_max {
+                *component = component.min(max[i]);
+            }
+        }
+        point
+    }
+}
+
+fn is_point(args: &[PragmaArg]) -> bool {
+    args.first()
+        .is_some_and(|a| a.key.is_none() && a.value == "point")
+}
+
+// `#pragma name()`, `#pragma name(true)` or `#pragma name(false)`
+fn parse_flag(name: &str, args: &[PragmaArg]) -> Result<bool, String> {
+    match args.first().map(|a| a.value) {
+        None | Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(other) => Err(format!("invalid {name} \"{other}\"")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorKind {
+    Vec3,
+    Vec4,
+    // a vec3 backed by an AE 3D point, in layer pixels
+    Point3d,
+}
+
+impl VectorKind {
+    // The kind named by the first positional argument of an input pragma
+    fn declared_by(args: &[PragmaArg]) -> Option<Self> {
+        match args.first().filter(|a| a.key.is_none())?.value {
+            "vec3" => Some(VectorKind::Vec3),
+            "vec4" => Some(VectorKind::Vec4),
+            "point3d" => Some(VectorKind::Point3d),
+            _ => None,
+        }
+    }
+
+    pub fn components(self) -> usize {
+        match self {
+            VectorKind::Vec3 | VectorKind::Point3d => 3,
+            VectorKind::Vec4 => 4,
+        }
+    }
+}
+
+// #pragma input(vec3, name="offset", default=[0, 0, 1], min=-1, max=[1, 1, 2])
+// min and max take one value for every component or a list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorInput {
+    pub name: String,
+    pub kind: VectorKind,
+    pub default: [f32; 4],
+    pub min: Option<[f32; 4]>,
+    pub max: Option<[f32; 4]>,
+}
+
+impl VectorInput {
+    fn parse(args: &[PragmaArg], line: &str) -> Result<Self, String> {
+        let kind = VectorKind::declared_by(args).expect("checked by the caller");
+        let mut name = None;
+        let mut out = VectorInput {
+            name: String::new(),
+            kind,
+            default: [0.0; 4],
+            min: None,
+            max: None,
+        };
+
+        for arg in args.iter().skip(1) {
+            match arg.key {
+                Some("name") => name = Some(arg.value.to_owned()),
+                Some("default") => out.default = parse_components(arg.value, kind)?,
+                Some("min") => out.min = Some(parse_components(arg.value, kind)?),
+                Some("max") => out.max = Some(parse_components(arg.value, kind)?),
+                _ if arg.is_plugin_input_key() => {}
+                _ => {
+                    return Err(format!(
+                        "unknown {kind:?} input field \"{}\"",
+                        arg.raw.trim()
+                    ))
+                }
+            }
+        }
+
+        out.name = name.ok_or_else(|| format!("input pragma has no name: {line}"))?;
+        Ok(out)
+    }
+
+    // Clamps a value read from the params to the pragma's min and max
+    pub fn clamp(&self, mut value: [f32; 4]) -> [f32; 4] {
+        for (i, component) in value.iter_mut().enumerate() {
+            if let Some(min) = self.min {
+                *component = component.max(min[i]);
+            }
+            if let Some(max) = self.max {
+                *component = component.min(max[i]);
+            }
+        }
+        value
+    }
+}
+
+fn parse_components(value: &str, kind: VectorKind) -> Result<[f32; 4], String> {
+    parse_list(value, kind.components())
+        .ok_or_else(|| format!("invalid {kind:?} value \"{value}\""))
+}
+
+// `[x, y, ...]` of `len` numbers, or a single number for every component
+fn parse_list(value: &str, len: usize) -> Option<[f32; 4]> {
+    let parse = |v: &str| v.trim().parse::<f32>().ok();
+
+    let Some(list) = value
+        .trim()
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+    else {
+        return Some([parse(value)?; 4]);
+    };
+
+    let values = list.split(',').map(parse).collect::<Option<Vec<_>>>()?;
+    if values.len() != len || len > 4 {
+        return None;
+    }
+
+    let mut out = [0.0; 4];
+    out[..values.len()].copy_from_slice(&values);
+    Some(out)
+}
+
+// What an image input shows when its layer param is empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    Transparent,
+    Black,
+    White,
+    Checker,
+    // the layer the effect is applied to
+    MainInput,
+}
+
+impl Fallback {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "transparent" => Ok(Fallback::Transparent),
+            "black" => Ok(Fallback::Black),
+            "white" => Ok(Fallback::White),
+            "checker" => Ok(Fallback::Checker),
+            "main_input" => Ok(Fallback::MainInput),
+            other => Err(format!(
+                "unknown fallback \"{other}\", expected one of transparent, black, white, checker or main_input"
+            )),
+        }
+    }
+}
+
+// What a float input measures, pixel values are scaled so previews
+// at a lower resolution look the same as full resolution renders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Pixels,
+}
+
+impl Units {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "pixels" => Ok(Units::Pixels),
+            other => Err(format!("unknown units \"{other}\", expected pixels")),
+        }
+    }
+}
+
+// How a float input is shown. The slider holds the shown value,
+// converted to and from the value the shader receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatUi {
+    // an AE angle param in degrees, the shader receives radians
+    Angle,
+    // the slider shows the value times 100
+    Percent,
+    Pixels,
+    // the slider shows the base 10 exponent of the value
+    Log,
+}
+
+impl FloatUi {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "angle" => Ok(FloatUi::Angle),
+            "percent" => Ok(FloatUi::Percent),
+            "pixels" => Ok(FloatUi::Pixels),
+            "log" => Ok(FloatUi::Log),
+            other => Err(format!(
+                "unknown ui \"{other}\", expected one of angle, percent, pixels or log"
+            )),
+        }
+    }
+
+    // An error if a float with this range can not use the ui,
+    // log sliders need every value above 0 to take its exponent.
+    pub fn check_range(self, min: f32, default: f32, max: f32) -> Result<(), String> {
+        if self == FloatUi::Log && [min, default, max].iter().any(|v| *v <= 0.0) {
+            return Err("uses ui=log, its min, default and max must be above 0.".to_owned());
+        }
+
+        Ok(())
+    }
+
+    pub fn to_shown(self, value: f32) -> f64 {
+        let value = value as f64;
+        match self {
+            FloatUi::Angle => value.to_degrees(),
+            FloatUi::Percent => value * 100.0,
+            FloatUi::Pixels => value,
+            FloatUi::Log => value.max(f64::MIN_POSITIVE).log10(),
+        }
+    }
+
+    pub fn from_shown(self, value: f64) -> f32 {
+        (match self {
+            FloatUi::Angle => value.to_radians(),
+            FloatUi::Percent => value / 100.0,
+            FloatUi::Pixels => value,
+            FloatUi::Log => 10f64.powf(value),
+        }) as f32
+    }
+}
+
+// Where a point input's origin is and what it measures. AE points
+// are in full resolution layer pixels with the origin top left.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PointSpace {
+    // the pixels the shader renders
+    #[default]
+    Pixels,
+    // 0 to 1 across the layer
+    Normalized,
+    // -1 to 1 across the layer, 0 at its center
+    Centered,
+}
+
+impl PointSpace {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "pixels" => Ok(PointSpace::Pixels),
+            "normalized" => Ok(PointSpace::Normalized),
+            "centered" => Ok(PointSpace::Centered),
+            other => Err(format!(
+                "unknown space \"{other}\", expected one of pixels, normalized or centered"
+            )),
+        }
+    }
+
+    // Layer pixels into this space at full resolution
+    pub fn from_layer(self, point: [f32; 2], size: [f32; 2], y_up: bool) -> [f32; 2] {
+        let [x, y] = point;
+        let y = if y_up { size[1] - y } else { y };
+        match self {
+            PointSpace::Pixels => [x, y],
+            PointSpace::Normalized => [x / size[0], y / size[1]],
+            PointSpace::Centered => [x / size[0] * 2.0 - 1.0, y / size[1] * 2.0 - 1.0],
+        }
+    }
+
+    // A point in this space at full resolution into what the shader sees,
+    // `scale` takes full resolution pixels to the pixels the shader renders.
+    pub fn to_render(self, point: [f32; 2], scale: [f32; 2]) -> [f32; 2] {
+        match self {
+            PointSpace::Pixels => [point[0] * scale[0], point[1] * scale[1]],
+            PointSpace::Normalized | PointSpace::Centered => point,
+        }
+    }
+
+    // A point in this space at full resolution back to layer pixels
+    pub fn to_layer(self, point: [f32; 2], size: [f32; 2], y_up: bool) -> [f32; 2] {
+        let [x, y] = match self {
+            PointSpace::Pixels => point,
+            PointSpace::Normalized => [point[0] * size[0], point[1] * size[1]],
+            PointSpace::Centered => [
+                (point[0] + 1.0) / 2.0 * size[0],
+                (point[1] + 1.0) / 2.0 * size[1],
+            ],
+        };
+        [x, if y_up { size[1] - y } else { y }]
+    }
+}
+
+// visible_if="mode==2 && strength>0.5 || useNoise"
+// Comparisons of other inputs against numbers, joined with `&&` and `||`,
+// `&&` binding tighter. A bare name is true when the input is not 0 and
+// `!name` when it is. Booleans read as 1 and 0, labeled ints as their value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    // true if every comparison of any one group holds
+    any: Vec<Vec<Comparison>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Comparison {
+    input: String,
+    op: CompareOp,
+    value: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Condition {
+    fn parse(value: &str) -> Result<Self, String> {
+        let any = value
+            .split("||")
+            .map(|all| all.split("&&").map(Comparison::parse).collect())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("{e} in condition \"{value}\""))?;
+
+        Ok(Condition { any })
+    }
+
+    // The inputs the condition reads
+    pub fn inputs(&self) -> impl Iterator<Item = &str> {
+        self.any.iter().flatten().map(|c| c.input.as_str())
+    }
+
+    // Inputs `value` can not read count as holding,
+    // so a broken condition never hides an input for good.
+    pub fn holds(&self, value: impl Fn(&str) -> Option<f32>) -> bool {
+        self.any.iter().any(|all| {
+            all.iter().all(|c| {
+                value(&c.input).is_none_or(|v| match c.op {
+                    CompareOp::Eq => (v - c.value).abs() < 1e-4,
+                    CompareOp::Ne => (v - c.value).abs() >= 1e-4,
+                    CompareOp::Lt => v < c.value,
+                    CompareOp::Le => v <= c.value,
+                    CompareOp::Gt => v > c.value,
+                    CompareOp::Ge => v >= c.value,
+                })
+            })
+        })
+    }
+}
+
+impl Comparison {
+    fn parse(term: &str) -> Result<Self, String> {
+        let term = term.trim();
+
+        // two character operators first so `<=` is not read as `<`
+        const OPS: [(&str, CompareOp); 6] = [
+            ("==", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("<", CompareOp::Lt),
+            (">", CompareOp::Gt),
+        ];
+
+        let (input, op, value) = match OPS
+            .iter()
+            .find_map(|(s, op)| term.split_once(s).map(|(input, value)| (input, *op, value)))
+        {
+            Some((input, op, value)) => {
+                let value = match value.trim() {
+                    "true" => 1.0,
+                    "false" => 0.0,
+                    v => v.parse().map_err(|_| format!("invalid value \"{v}\""))?,
+                };
+                (input.trim(), op, value)
+            }
+            None => match term.strip_prefix('!') {
+                Some(input) => (input.trim(), CompareOp::Eq, 0.0),
+                None => (term, CompareOp::Ne, 0.0),
+            },
+        };
+
+        let is_name =
+            !input.is_empty() && input.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_name {
+            return Err(format!("invalid input name \"{input}\""));
+        }
+
+        Ok(Comparison {
+            input: input.to_owned(),
+            op,
+            value,
+        })
+    }
+}
+
+// #pragma simulation(start=0, checkpoint=30)
+// Persistent passes are pre rolled from `start` to the requested
+// frame, saving their state every `checkpoint_interval` frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Simulation {
+    pub start: i32,
+    pub checkpoint_interval: u32,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            checkpoint_interval: 30,
+        }
+    }
+}
+
+impl Simulation {
+    fn parse(args: &[PragmaArg]) -> Result<Self, String> {
+        let mut out = Simulation::default();
+
+        for arg in args {
+            match arg.key {
+                Some("start") => {
+                    out.start = arg
+                        .value
+                        .parse()
+                        .map_err(|_| format!("invalid simulation start \"{}\"", arg.value))?;
+                }
+                Some("checkpoint") => {
+                    out.checkpoint_interval = arg
+                        .value
+                        .parse::<u32>()
+                        .map_err(|_| format!("invalid simulation checkpoint \"{}\"", arg.value))?
+                        .max(1);
+                }
+                _ => return Err(format!("unknown simulation field \"{}\"", arg.raw.trim())),
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+// How far from the current time an image input is sampled,
+// written as `-1f` for frames or `-0.5s` for seconds.
+// A bare number is a frame count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeOffset {
+    Frames(f32),
+    Seconds(f32),
+}
+
+impl TimeOffset {
+    // None if the offset is zero
+    fn parse(value: &str) -> Result<Option<Self>, String> {
+        let (number, seconds) = match value.strip_suffix('s') {
+            Some(number) => (number, true),
+            None => (value.strip_suffix('f').unwrap_or(value), false),
+        };
+
+        let number: f32 = number
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid time_offset \"{value}\""))?;
+
+        if number == 0.0 {
+            return Ok(None);
+        }
+
+        Ok(Some(if seconds {
+            TimeOffset::Seconds(number)
+        } else {
+            TimeOffset::Frames(number)
+        }))
+    }
+
+    // The offset in AE time units
+    pub fn to_ae_time(self, time_step: i32, time_scale: u32) -> i32 {
+        match self {
+            TimeOffset::Frames(frames) => (frames * time_step as f32).round() as i32,
+            TimeOffset::Seconds(secs) => (secs * time_scale as f32).round() as i32,
+        }
+    }
+}
+
+// A single side of an expansion, either a literal pixel count
+// or the name of a float or int input to read it from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amount {
+    Pixels(f32),
+    Input(String),
+}
+
+impl Amount {
+    fn parse(value: &str) -> Self {
+        match value.parse::<f32>() {
+            Ok(px) => Amount::Pixels(px.max(0.0)),
+            Err(_) => Amount::Input(value.to_owned()),
+        }
+    }
+}
+
+// #pragma expand(32)
+// #pragma expand(all=radius)
+// #pragma expand(x=10, top=0, bottom=shadow_length)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Expansion {
+    // left, top, right, bottom
+    pub sides: [Option<Amount>; 4],
+}
+
+impl Expansion {
+    fn parse(args: &[PragmaArg]) -> Result<Self, String> {
+        let mut out = Expansion::default();
+
+        for arg in args {
+            let amount = Amount::parse(arg.value);
+            let sides: &[usize] = match arg.key {
+                None | Some("all") => &[0, 1, 2, 3],
+                Some("x") => &[0, 2],
+                Some("y") => &[1, 3],
+                Some("left") => &[0],
+                Some("top") => &[1],
+                Some("right") => &[2],
+                Some("bottom") => &[3],
+                Some(other) => return Err(format!("unknown expand field \"{other}\"")),
+            };
+
+            for side in sides {
+                out.sides[*side] = Some(amount.clone());
+            }
+        }
+
+        Ok(out)
+    }
+
+    // Resolves every side to a pixel count, `lookup` returns
+    // the current value of the named input.
+    pub fn resolve(&self, lookup: impl Fn(&str) -> Option<f32>) -> [f32; 4] {
+        self.sides.clone().map(|side| match side {
+            Some(Amount::Pixels(px)) => px,
+            Some(Amount::Input(name)) => lookup(&name).unwrap_or(0.0).max(0.0),
+            None => 0.0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PragmaArg<'a> {
+    key: Option<&'a str>,
+    value: &'a str,
+    // the argument as written, used to rebuild pragmas
+    raw: &'a str,
+}
+
+impl PragmaArg<'_> {
+    // `hidden` is the only positional argument the plugin takes
+    fn is_plugin_input_key(&self) -> bool {
+        match self.key {
+            Some(key) => INPUT_KEYS.contains(&key),
+            None => self.value == "hidden",
+        }
+    }
+}
+
+// Splits `#pragma name(a, key=b, ...)` into the name and its arguments
+fn parse_pragma(line: &str) -> Option<(&str, Vec<PragmaArg<'_>>)> {
+    let rest = line.trim_start().strip_prefix("#pragma")?.trim_start();
+    let open = rest.find('(')?;
+    let close = rest.rfind(')')?;
+
+    if close < open {
+        return None;
+    }
+
+    let args = split_args(&rest[open + 1..close])
+        .into_iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((key, value)) => PragmaArg {
+                key: Some(key.trim()),
+                value: unquote(value.trim()),
+                raw: arg,
+            },
+            None => PragmaArg {
+                key: None,
+                value: unquote(arg.trim()),
+                raw: arg,
+            },
+        })
+        .collect();
+
+    Some((rest[..open].trim(), args))
+}
+
+// Splits on commas that are not nested in brackets or quotes
+fn split_args(args: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in args.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('[' | '(', None) => depth += 1,
+            (']' | ')', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                out.push(&args[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    if !args[start..].trim().is_empty() {
+        out.push(&args[start..]);
+    }
+
+    out
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim_matches(|c| c == '"' || c == '\'')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fallbacks() {
+        assert_eq!(Fallback::parse("main_input"), Ok(Fallback::MainInput));
+        assert_eq!(Fallback::parse("transparent"), Ok(Fallback::Transparent));
+        assert_eq!(Fallback::parse("black"), Ok(Fallback::Black));
+        assert_eq!(Fallback::parse("white"), Ok(Fallback::White));
+        assert_eq!(Fallback::parse("checker"), Ok(Fallback::Checker));
+        assert!(Fallback::parse("red")
+            .unwrap_err()
+            .contains("unknown fallback \"red\""));
+    }
+
+    #[test]
+    fn strips_fallback_from_input_pragmas() {
+        let src = "#pragma input(image, name=\"mask\", fallback=white)\nvoid main() {}";
+        let (stripped, annotations) = extract(src).unwrap();
+
+        assert_eq!(
+            stripped,
+            "#pragma input(image, name=\"mask\")\nvoid main() {}\n"
+        );
+        assert_eq!(
+            annotations.input("mask").and_then(|a| a.fallback),
+            Some(Fallback::White)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fallbacks() {
+        assert!(extract("#pragma input(image, name=\"mask\", fallback=red)").is_err());
+    }
+
+    #[test]
+    fn clamps_pixel_points_before_scaling() {
+        let src = "#pragma input(point, name=\"p\", min=[0, 0], max=[100, 100])";
+        let (_, annotations) = extract(src).unwrap();
+        let input = annotations.input("p").unwrap();
+
+        let space = PointSpace::Pixels;
+        let full = input.clamp_point(space.from_layer([150.0, 50.0], [200.0, 200.0], false));
+        assert_eq!(full, [100.0, 50.0]);
+
+        // at half resolution the bound lands on the same layer pixel
+        assert_eq!(space.to_render(full, [0.5, 0.5]), [50.0, 25.0]);
+    }
+
+    #[test]
+    fn pixel_sliders_are_pixel_valued() {
+        let src = "#pragma input(float, name=\"a\", ui=pixels)\n\
+                   #pragma input(float, name=\"b\", units=pixels)\n\
+                   #pragma input(float, name=\"c\", ui=percent)";
+        let (_, annotations) = extract(src).unwrap();
+
+        assert!(annotations.input("a").unwrap().in_pixels());
+        assert!(annotations.input("b").unwrap().in_pixels());
+        assert!(!annotations.input("c").unwrap().in_pixels());
+    }
+
+    #[test]
+    fn normalized_points_ignore_the_render_scale() {
+        let space = PointSpace::Normalized;
+        let point = space.from_layer([50.0, 150.0], [200.0, 200.0], true);
+        assert_eq!(point, [0.25, 0.25]);
+        assert_eq!(space.to_render(point, [0.5, 0.5]), point);
+        assert_eq!(space.to_layer(point, [200.0, 200.0], true), [50.0, 150.0]);
+    }
+
+    #[test]
+    fn parses_expansions() {
+        let (stripped, annotations) = extract("#pragma expand(32)").unwrap();
+        assert_eq!(stripped, "\n");
+        let sides = annotations.expansion.unwrap().sides;
+        assert!(sides.iter().all(|s| *s == Some(Amount::Pixels(32.0))));
+
+        let src = "#pragma expand(x=10, top=-5, bottom=shadow_length)";
+        let expansion = extract(src).unwrap().1.expansion.unwrap();
+        assert_eq!(
+            expansion.sides,
+            [
+                Some(Amount::Pixels(10.0)),
+                Some(Amount::Pixels(0.0)),
+                Some(Amount::Pixels(10.0)),
+                Some(Amount::Input("shadow_length".to_owned())),
+            ]
+        );
+
+        let lookup = |name: &str| (name == "shadow_length").then_some(24.5);
+        assert_eq!(expansion.resolve(lookup), [10.0, 0.0, 10.0, 24.5]);
+        assert_eq!(expansion.resolve(|_| Some(-3.0)), [10.0, 0.0, 10.0, 0.0]);
+
+        let error = extract("#pragma expand(middle=4)").unwrap_err();
+        assert_eq!(error, "unknown expand field \"middle\"");
+    }
+
+    #[test]
+    fn parses_time_offsets() {
+        assert_eq!(TimeOffset::parse("2"), Ok(Some(TimeOffset::Frames(2.0))));
+        assert_eq!(TimeOffset::parse("-3f"), Ok(Some(TimeOffset::Frames(-3.0))));
+        assert_eq!(
+            TimeOffset::parse("0.5s"),
+            Ok(Some(TimeOffset::Seconds(0.5)))
+        );
+        assert_eq!(TimeOffset::parse("0s"), Ok(None));
+        assert_eq!(
+            TimeOffset::parse("soon"),
+            Err("invalid time_offset \"soon\"".to_owned())
+        );
+
+        // 30 fps in AE's units
+        assert_eq!(TimeOffset::Frames(-3.0).to_ae_time(100, 3000), -300);
+        assert_eq!(TimeOffset::Seconds(0.5).to_ae_time(100, 3000), 1500);
+
+        let src = "#pragma input(image, name=\"prev\", time_offset=-1)";
+        let (stripped, annotations) = extract(src).unwrap();
+        assert_eq!(stripped, "#pragma input(image, name=\"prev\")\n");
+        assert_eq!(
+            annotations.input("prev").unwrap().time_offset,
+            Some(TimeOffset::Frames(-1.0))
+        );
+        assert!(annotations.reads_other_times());
+    }
+
+    #[test]
+    fn parses_vector_inputs() {
+        let src =
+            "#pragma input(vec3, name=\"offset\", default=[0, 0, 1], min=-1, max=[1, 1, 2])\n\
+                   #pragma input(vec4, name=\"tint\", default=0.5, label=\"Tint\")\n\
+                   #pragma input(point3d, name=\"light\", default=[10, 20, -30])";
+        let (stripped, annotations) = extract(src).unwrap();
+
+        // tweak_shader never sees them, the lines stay for error line numbers
+        assert_eq!(stripped, "\n\n\n");
+
+        let offset = annotations.vector("offset").unwrap();
+        assert_eq!(offset.kind, VectorKind::Vec3);
+        assert_eq!(offset.default, [0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(offset.min, Some([-1.0; 4]));
+        assert_eq!(offset.max, Some([1.0, 1.0, 2.0, 0.0]));
+        assert_eq!(offset.clamp([5.0, -5.0, 1.5, 0.0]), [1.0, -1.0, 1.5, 0.0]);
+
+        let tint = annotations.vector("tint").unwrap();
+        assert_eq!(tint.kind, VectorKind::Vec4);
+        assert_eq!(tint.default, [0.5; 4]);
+        assert_eq!(
+            annotations.input("tint").unwrap().label.as_deref(),
+            Some("Tint")
+        );
+
+        let light = annotations.vector("light").unwrap();
+        assert_eq!(light.kind, VectorKind::Point3d);
+        assert_eq!(light.kind.components(), 3);
+        assert_eq!(light.default, [10.0, 20.0, -30.0, 0.0]);
+
+        let names: Vec<_> = annotations
+            .vectors
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(names, ["offset", "tint", "light"]);
+    }
+
+    #[test]
+    fn rejects_malformed_vector_inputs() {
+        let error = extract("#pragma input(vec3, name=\"v\", default=[1, 2])").unwrap_err();
+        assert_eq!(error, "invalid Vec3 value \"[1, 2]\"");
+
+        let error = extract("#pragma input(vec4, name=\"v\", step=1)").unwrap_err();
+        assert_eq!(error, "unknown Vec4 input field \"step=1\"");
+
+        let error = extract("#pragma input(point3d, default=0)").unwrap_err();
+        assert!(error.starts_with("input pragma has no name"));
+    }
+
+    #[test]
+    fn parses_display_keys() {
+        let src = "#pragma input(float, name=\"a\", label=\"Amount, Fine\", group=\"Noise\", \
+                   order=-2, hidden, default=0.5)";
+        let (stripped, annotations) = extract(src).unwrap();
+        assert_eq!(stripped, "#pragma input(float, name=\"a\", default=0.5)\n");
+
+        let input = annotations.input("a").unwrap();
+        assert_eq!(input.label.as_deref(), Some("Amount, Fine"));
+        assert_eq!(input.group.as_deref(), Some("Noise"));
+        assert_eq!(input.order, Some(-2));
+        assert!(input.hidden);
+
+        let src = "#pragma input(float, name=\"b\", label=\"Bias\")";
+        let input = extract(src).unwrap().1.inputs.remove("b").unwrap();
+        assert_eq!(input.group, None);
+        assert_eq!(input.order, None);
+        assert!(!input.hidden);
+
+        let error = extract("#pragma input(float, name=\"c\", order=first)").unwrap_err();
+        assert_eq!(error, "invalid order \"first\"");
+    }
+
+    #[test]
+    fn log_sliders_need_positive_ranges() {
+        assert!(FloatUi::Log.check_range(0.001, 1.0, 1000.0).is_ok());
+        for (min, default, max) in [(0.0, 1.0, 10.0), (0.1, -1.0, 10.0), (-10.0, -1.0, 0.0)] {
+            let error = FloatUi::Log.check_range(min, default, max).unwrap_err();
+            assert_eq!(
+                error,
+                "uses ui=log, its min, default and max must be above 0."
+            );
+        }
+
+        // other uis take any range
+        assert!(FloatUi::Percent.check_range(-1.0, 0.0, 1.0).is_ok());
+
+        let src = "#pragma input(float, name=\"e\", default=1.0, min=0.001, max=1000.0, ui=log)";
+        let (stripped, annotations) = extract(src).unwrap();
+        assert_eq!(
+            stripped,
+            "#pragma input(float, name=\"e\", default=1.0, min=0.001, max=1000.0)\n"
+        );
+        assert_eq!(annotations.input("e").unwrap().ui, Some(FloatUi::Log));
+    }
+
+    // reads inputs from `values`, any other input is unreadable
+    fn holds(condition: &str, values: &[(&str, f32)]) -> bool {
+        Condition::parse(condition).unwrap().holds(|name| {
+            values
+                .iter()
+                .find(|(input, _)| *input == name)
+                .map(|(_, v)| *v)
+        })
+    }
+
+    #[test]
+    fn parses_every_comparison() {
+        let condition = Condition::parse("a==1 && b!=2 && c<3 && d<=4 && e>5 && f>=6").unwrap();
+        let ops: Vec<_> = condition.any[0].iter().map(|c| (c.op, c.value)).collect();
+        assert_eq!(
+            ops,
+            [
+                (CompareOp::Eq, 1.0),
+                (CompareOp::Ne, 2.0),
+                (CompareOp::Lt, 3.0),
+                (CompareOp::Le, 4.0),
+                (CompareOp::Gt, 5.0),
+                (CompareOp::Ge, 6.0),
+            ]
+        );
+        assert_eq!(
+            condition.inputs().collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+    }
+
+    #[test]
+    fn parses_bare_names_and_booleans() {
+        let condition = Condition::parse(" useNoise || !invert || on==true && off==false").unwrap();
+        let terms: Vec<Vec<_>> = condition
+            .any
+            .iter()
+            .map(|all| {
+                all.iter()
+                    .map(|c| (c.input.as_str(), c.op, c.value))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            terms,
+            [
+                vec![("useNoise", CompareOp::Ne, 0.0)],
+                vec![("invert", CompareOp::Eq, 0.0)],
+                vec![("on", CompareOp::Eq, 1.0), ("off", CompareOp::Eq, 0.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        let error = Condition::parse("mode==two").unwrap_err();
+        assert_eq!(error, "invalid value \"two\" in condition \"mode==two\"");
+
+        let error = Condition::parse("mode-1>0").unwrap_err();
+        assert!(error.starts_with("invalid input name \"mode-1\""));
+
+        assert!(Condition::parse("").is_err());
+        assert!(Condition::parse("a==1 ||").is_err());
+        assert!(extract("#pragma input(float, name=\"x\", visible_if=\"==1\")").is_err());
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let condition = "mode==2 && strength>0.5 || useNoise";
+        assert!(holds(
+            condition,
+            &[("mode", 2.0), ("strength", 1.0), ("useNoise", 0.0)]
+        ));
+        assert!(holds(
+            condition,
+            &[("mode", 0.0), ("strength", 0.0), ("useNoise", 1.0)]
+        ));
+        assert!(!holds(
+            condition,
+            &[("mode", 2.0), ("strength", 0.0), ("useNoise", 0.0)]
+        ));
+        assert!(!holds(
+            condition,
+            &[("mode", 1.0), ("strength", 1.0), ("useNoise", 0.0)]
+        ));
+    }
+
+    #[test]
+    fn compares_within_a_tolerance() {
+        assert!(holds("scale==0.1", &[("scale", 0.1 + 1e-5)]));
+        assert!(!holds("scale!=0.1", &[("scale", 0.1 + 1e-5)]));
+        assert!(holds("scale<=0.1", &[("scale", 0.1)]));
+        assert!(!holds("scale<0.1", &[("scale", 0.1)]));
+        assert!(holds("!invert", &[("invert", 0.0)]));
+        assert!(!holds("invert", &[("invert", 0.0)]));
+    }
+
+    #[test]
+    fn unreadable_inputs_count_as_holding() {
+        assert!(holds("missing==3", &[]));
+        assert!(holds("!missing", &[]));
+        // only the readable comparison can fail the group
+        assert!(holds("missing>1 && mode==2", &[("mode", 2.0)]));
+        assert!(!holds("missing>1 && mode==2", &[("mode", 1.0)]));
+    }
+}
@@ -0,0 +1,474 @@
+// Audio inputs. `audio` and `audiofft` inputs are backed by a layer param,
+// the layer's audio around the current time is checked out and bound under
+// the input's name as a waveform or spectrum texture, with levels written
+// to the `ae_<input name>_levels` host uniform.
+
+use crate::host_uniforms;
+use crate::types::FrameTime;
+use after_effects::{self as ae, Error, InData};
+use after_effects_sys as ae_sys;
+use tweak_shader::input_type::{InputType, InputVariant};
+use tweak_shader::{wgpu, RenderContext, TextureDesc};
+
+const SAMPLE_RATE: f64 = 44_100.0;
+const CHANNELS: usize = 2;
+
+// smallest window analyzed, about 46ms at 44.1kHz
+const MIN_FFT_SIZE: usize = 2048;
+
+// texture width when the input does not set `max_samples`
+const DEFAULT_SAMPLES: usize = 512;
+const DEFAULT_BINS: usize = 256;
+
+// the spectrum maps this range of decibels to 0 - 1
+const MIN_DB: f32 = -90.0;
+
+// upper edges of the band energies in hz, the last band takes the rest
+const BAND_EDGES: [f32; 3] = [250.0, 2_000.0, 6_000.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioKind {
+    Waveform,
+    Spectrum,
+}
+
+impl AudioKind {
+    pub fn of(ty: &InputType) -> Option<Self> {
+        match ty {
+            InputType::Audio(..) => Some(AudioKind::Waveform),
+            InputType::AudioFft(..) => Some(AudioKind::Spectrum),
+            _ => None,
+        }
+    }
+
+    pub fn of_variant(variant: InputVariant) -> Option<Self> {
+        match variant {
+            InputVariant::Audio => Some(AudioKind::Waveform),
+            InputVariant::AudioFft => Some(AudioKind::Spectrum),
+            _ => None,
+        }
+    }
+}
+
+// Texture width the shader asked for, samples or frequency bins
+pub fn texture_width(ty: &InputType) -> usize {
+    match ty {
+        InputType::Audio(_, max_samples) => max_samples.map_or(DEFAULT_SAMPLES, |m| m as usize),
+        InputType::AudioFft(_, max_samples) => max_samples.map_or(DEFAULT_BINS, |m| m as usize),
+        _ => 0,
+    }
+    .max(1)
+}
+
+// The time the audio inputs were last bound at. Their textures and levels
+// stay in the render context, so binding the same time again is skipped.
+#[derive(Debug, Default)]
+pub struct AudioCache {
+    bound_at: Option<FrameTime>,
+}
+
+impl AudioCache {
+    // Forgets the bound time, the layers' audio may have changed since
+    pub fn invalidate(&mut self) {
+        self.bound_at = None;
+    }
+
+    // True if the inputs still hold the audio at `time`, otherwise
+    // notes `time` as bound for the caller to load.
+    pub fn is_bound_at(&mut self, time: FrameTime) -> bool {
+        if self.bound_at == Some(time) {
+            return true;
+        }
+
+        self.bound_at = Some(time);
+        false
+    }
+}
+
+// One channel's analysis of the checked out window
+#[derive(Debug, Clone)]
+struct Analysis {
+    waveform: Vec<f32>,
+    spectrum: Vec<f32>,
+    rms: f32,
+    bands: [f32; 4],
+}
+
+// Checks out the audio of the layer param `layer_param` around `seconds`
+// of layer time and binds it under `name`. Disconnected layers and silence
+// bind a flat texture so the shader always has something to sample.
+#[allow(clippy::too_many_arguments)]
+pub fn load(
+    ctx: &mut RenderContext,
+    in_data: InData,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    name: &str,
+    kind: AudioKind,
+    width: usize,
+    layer_param: i32,
+    seconds: f64,
+) {
+    let fft_size = (width * 2).next_power_of_two().max(MIN_FFT_SIZE);
+    let window = match kind {
+        AudioKind::Waveform => width.max(fft_size),
+        AudioKind::Spectrum => fft_size,
+    };
+
+    let channels = checkout_samples(in_data, layer_param, seconds, window)
+        .unwrap_or_else(|_| vec![vec![0.0; window]; CHANNELS]);
+
+    let analyses: Vec<Analysis> = channels
+        .iter()
+        .map(|samples| analyze(samples, width, fft_size))
+        .collect();
+
+    let rows: Vec<&[f32]> = analyses
+        .iter()
+        .map(|a| match kind {
+            AudioKind::Waveform => a.waveform.as_slice(),
+            AudioKind::Spectrum => a.spectrum.as_slice(),
+        })
+        .collect();
+
+    let data = texture_data(&rows, width);
+    ctx.load_texture(
+        name,
+        TextureDesc {
+            width: width as u32,
+            height: rows.len() as u32,
+            stride: Some(width as u32 * 8),
+            data: &data,
+            format: wgpu::TextureFormat::Rgba16Float,
+        },
+        device,
+        queue,
+    );
+
+    // levels are averaged over the channels
+    let count = analyses.len().max(1) as f32;
+    let mut levels = [0.0; 5];
+    for analysis in &analyses {
+        for (level, band) in levels.iter_mut().zip(analysis.bands) {
+            *level += band / count;
+        }
+        levels[4] += analysis.rms / count;
+    }
+
+    host_uniforms::write_f32s(ctx, &host_uniforms::audio_levels_name(name), &levels);
+}
+
+// Deinterleaved samples of `window` frames centered on `seconds`
+fn checkout_samples(
+    in_data: InData,
+    layer_param: i32,
+    seconds: f64,
+    window: usize,
+) -> Result<Vec<Vec<f32>>, Error> {
+    let callbacks = ae::pf::InteractCallbacks::new(in_data);
+
+    let start = (seconds * SAMPLE_RATE).round() as i32 - window as i32 / 2;
+    let audio = callbacks.checkout_layer_audio(
+        layer_param,
+        start,
+        window as i32,
+        SAMPLE_RATE as u32,
+        SAMPLE_RATE,
+        std::mem::size_of::<f32>() as i32,
+        CHANNELS as i32,
+        ae_sys::PF_SIGNED_FLOAT as i32,
+    )?;
+
+    let result = callbacks.get_audio_data(audio).map(|data| {
+        let mut channels = vec![vec![0.0; window]; CHANNELS];
+        let frames = (data.num_samples as usize).min(window);
+        let interleaved = data.as_f32_slice();
+        let source_channels = (data.num_channels as usize).max(1);
+
+        for frame in 0..frames {
+            for (channel, samples) in channels.iter_mut().enumerate() {
+                // mono layers fill every channel
+                let source = channel.min(source_channels - 1);
+                samples[frame] = interleaved
+                    .get(frame * source_channels + source)
+                    .copied()
+                    .unwrap_or(0.0);
+            }
+        }
+
+        channels
+    });
+
+    callbacks.checkin_layer_audio(audio)?;
+    result
+}
+
+fn analyze(samples: &[f32], width: usize, fft_size: usize) -> Analysis {
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+
+    // the `width` samples centered on the current time
+    let start = (samples.len() / 2).saturating_sub(width / 2);
+    let waveform = (0..width)
+        .map(|i| samples.get(start + i).copied().unwrap_or(0.0))
+        .collect();
+
+    let center = samples.len() / 2;
+    let fft_start = center.saturating_sub(fft_size / 2);
+    let fft_end = (fft_start + fft_size).min(samples.len());
+    let magnitudes = magnitudes(&samples[fft_start..fft_end], fft_size);
+
+    // `width` bins spread linearly over the half spectrum
+    let spectrum = (0..width)
+        .map(|i| {
+            let bin = i * magnitudes.len() / width;
+            let db = 20.0 * magnitudes[bin].max(1e-9).log10();
+            ((db - MIN_DB) / -MIN_DB).clamp(0.0, 1.0)
+        })
+        .collect();
+
+    let bin_hz = SAMPLE_RATE as f32 / fft_size as f32;
+    let mut bands = [0.0; 4];
+    let mut counts = [0usize; 4];
+    for (bin, magnitude) in magnitudes.iter().enumerate().skip(1) {
+        let hz = bin as f32 * bin_hz;
+        let band = BAND_EDGES.iter().position(|edge| hz < *edge).unwrap_or(3);
+        bands[band] += magnitude * magnitude;
+        counts[band] += 1;
+    }
+    for (band, count) in bands.iter_mut().zip(counts) {
+        *band = (*band / count.max(1) as f32).sqrt();
+    }
+
+    Analysis {
+        waveform,
+        spectrum,
+        rms,
+        bands,
+    }
+}
+
+// Hann windowed magnitudes of the first `fft_size / 2` bins,
+// normalized so a full scale sine peaks near 1.
+fn magnitudes(samples: &[f32], fft_size: usize) -> Vec<f32> {
+    let mut re = vec![0.0f32; fft_size];
+    let mut im = vec![0.0f32; fft_size];
+
+    for (i, sample) in samples.iter().enumerate() {
+        let hann = 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / (fft_size - 1) as f32).cos();
+        re[i] = sample * hann;
+    }
+
+    fft(&mut re, &mut im);
+
+    // the hann window halves the amplitude
+    let norm = 4.0 / fft_size as f32;
+    (0..fft_size / 2)
+        .map(|i| (re[i] * re[i] + im[i] * im[i]).sqrt() * norm)
+        .collect()
+}
+
+// In place iterative radix 2 fft, the length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -std::f32::consts::TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Rgba16Float rows, one per channel. Texels are stored in AE's ARGB
+// order like every other input, the value is in rgb and alpha is 1.
+fn texture_data(rows: &[&[f32]], width: usize) -> Vec<u8> {
+    let one = f16_bits(1.0).to_ne_bytes();
+    let mut data = Vec::with_capacity(rows.len() * width * 8);
+    for row in rows {
+        for value in row.iter().take(width) {
+            let value = f16_bits(*value).to_ne_bytes();
+            data.extend_from_slice(&one);
+            data.extend_from_slice(&value);
+            data.extend_from_slice(&value);
+            data.extend_from_slice(&value);
+        }
+    }
+    data
+}
+
+// Rounds to the nearest half float, ties to even
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x007f_ffff;
+
+    if value.is_nan() {
+        return 0x7e00;
+    }
+
+    // a carry out of the mantissa bumps the exponent, up to infinity
+    let magnitude = match exponent {
+        e if e >= 0x1f => 0x7c00,
+        e if e <= 0 => {
+            if e < -10 {
+                return sign;
+            }
+            round_shift(mantissa | 0x0080_0000, (14 - e) as u32)
+        }
+        e => round_shift(((e as u32) << 23) | mantissa, 13),
+    };
+
+    sign | magnitude as u16
+}
+
+// `value >> shift` rounded to nearest, ties to even
+fn round_shift(value: u32, shift: u32) -> u32 {
+    let half = 1 << (shift - 1);
+    let rest = value & ((1 << shift) - 1);
+    let out = value >> shift;
+    if rest > half || (rest == half && out & 1 == 1) {
+        out + 1
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(bin: usize, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (std::f32::consts::TAU * (bin * i) as f32 / len as f32).sin())
+            .collect()
+    }
+
+    fn peak(values: &[f32]) -> usize {
+        (0..values.len())
+            .max_by(|a, b| values[*a].total_cmp(&values[*b]))
+            .unwrap()
+    }
+
+    #[test]
+    fn fft_of_a_sine_peaks_at_its_bin() {
+        let n = 64;
+        let mut re = sine(5, n);
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+
+        let bins: Vec<f32> = (0..n / 2).map(|i| re[i].hypot(im[i])).collect();
+        assert_eq!(peak(&bins), 5);
+        assert!((bins[5] - n as f32 / 2.0).abs() < 1e-3, "{}", bins[5]);
+        assert!(bins[3] < 1e-3, "{}", bins[3]);
+    }
+
+    #[test]
+    fn fft_of_an_impulse_is_flat() {
+        let mut re = vec![0.0; 16];
+        let mut im = vec![0.0; 16];
+        re[0] = 1.0;
+        fft(&mut re, &mut im);
+
+        for (re, im) in re.iter().zip(&im) {
+            assert!((re - 1.0).abs() < 1e-6 && im.abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn full_scale_sines_peak_near_one() {
+        let fft_size = MIN_FFT_SIZE;
+        let magnitudes = magnitudes(&sine(100, fft_size), fft_size);
+
+        assert_eq!(magnitudes.len(), fft_size / 2);
+        assert_eq!(peak(&magnitudes), 100);
+        assert!((magnitudes[100] - 1.0).abs() < 0.01, "{}", magnitudes[100]);
+    }
+
+    #[test]
+    fn audio_is_bound_once_per_time() {
+        let at = |seconds: i32| FrameTime {
+            time: seconds * 30,
+            step: 1,
+            scale: 30,
+        };
+        let mut cache = AudioCache::default();
+
+        assert!(!cache.is_bound_at(at(1)));
+        assert!(cache.is_bound_at(at(1)));
+        assert!(!cache.is_bound_at(at(2)));
+
+        cache.invalidate();
+        assert!(!cache.is_bound_at(at(2)));
+    }
+
+    #[test]
+    fn f16_edges_convert_exactly() {
+        let cases = [
+            (0.0, 0x0000),
+            (-0.0, 0x8000),
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (0.5, 0x3800),
+            (65504.0, 0x7bff),
+            (f32::INFINITY, 0x7c00),
+            (f32::NEG_INFINITY, 0xfc00),
+            (f32::NAN, 0x7e00),
+            // smallest normal and subnormal
+            (2f32.powi(-14), 0x0400),
+            (2f32.powi(-24), 0x0001),
+            (-2f32.powi(-24), 0x8001),
+        ];
+
+        for (value, bits) in cases {
+            assert_eq!(f16_bits(value), bits, "{value:e}");
+        }
+    }
+
+    #[test]
+    fn f16_rounds_to_nearest_even() {
+        let cases = [
+            (1.0 / 3.0, 0x3555),
+            (0.1, 0x2e66),
+            // halfway cases go to the even neighbour
+            (1.0 + 2f32.powi(-11), 0x3c00),
+            (1.0 + 3.0 * 2f32.powi(-11), 0x3c02),
+            // a carry into the exponent, and past the largest half
+            (2.0 - 2f32.powi(-12), 0x4000),
+            (65520.0, 0x7c00),
+            // subnormals round too, half the smallest one goes to zero
+            (2f32.powi(-25), 0x0000),
+            (1.5 * 2f32.powi(-25), 0x0001),
+            (2f32.powi(-14) - 2f32.powi(-25), 0x0400),
+        ];
+
+        for (value, bits) in cases {
+            assert_eq!(f16_bits(value), bits, "{value:e}");
+        }
+    }
+}
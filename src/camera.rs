@@ -0,0 +1,252 @@
+// The comp's active camera and lights for the `ae_camera` and `ae_lights`
+// uniforms. Everything is in comp pixels, AE's world space, with y down
+// and the camera looking along +z.
+
+use crate::host_util::{column_major, comp_time};
+use crate::types::FrameTime;
+use after_effects::aegp::{suites, LayerHandle, LayerStream, LightType, ObjectType, TimeMode};
+use after_effects::{Error, InData, Time};
+
+// length of `ae_lights`, see `host_uniforms::LIGHTS`
+pub const MAX_LIGHTS: usize = 8;
+
+// std140 layout of `ae_camera`, see `host_uniforms::CAMERA`
+pub const CAMERA_FLOATS: usize = 37;
+
+// one `Light`, three vec4s
+const LIGHT_FLOATS: usize = 12;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Camera {
+    // comp pixels to camera space, column major
+    pub view: [f32; 16],
+    // camera space to comp pixels after the divide by w, column major
+    pub projection: [f32; 16],
+    // distance to the image plane, pixels
+    pub zoom: f32,
+    pub focus_distance: f32,
+    pub aperture: f32,
+    // 0 to 1
+    pub blur_level: f32,
+    // 1.0 if the comp has a camera layer, AE's default camera otherwise
+    pub present: f32,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: [f32; 3],
+    // unit vector the light points along
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    // 1.0 at 100%
+    pub intensity: f32,
+    // degrees, spot lights only
+    pub cone_angle: f32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LightKind {
+    #[default]
+    None = 0,
+    Parallel = 1,
+    Spot = 2,
+    Point = 3,
+    Ambient = 4,
+}
+
+impl Camera {
+    pub fn collect(in_data: InData, time: FrameTime) -> Result<Self, Error> {
+        let comp_time = comp_time(in_data, time)?;
+        let interface = suites::PFInterface::new()?;
+        let camera_layer = interface.effect_camera(in_data.effect(), comp_time)?;
+        let (matrix, zoom, width, height) =
+            interface.effect_camera_matrix(in_data.effect(), comp_time)?;
+
+        let stream = |which| {
+            camera_layer
+                .as_ref()
+                .and_then(|layer| stream_f64(layer, which, comp_time))
+                .unwrap_or(0.0) as f32
+        };
+
+        let (half_width, half_height) = (width as f64 / 2.0, height as f64 / 2.0);
+
+        // already column major, see `host_util::column_major`
+        #[rustfmt::skip]
+        let projection = [
+            zoom,       0.0,         0.0, 0.0,
+            0.0,        zoom,        0.0, 0.0,
+            half_width, half_height, 1.0, 1.0,
+            0.0,        0.0,         0.0, 0.0,
+        ];
+
+        Ok(Self {
+            view: column_major(&affine_inverse(&matrix.mat)),
+            projection: projection.map(|v| v as f32),
+            zoom: zoom as f32,
+            focus_distance: stream(LayerStream::FocusDistance),
+            aperture: stream(LayerStream::Aperture),
+            blur_level: stream(LayerStream::BlurLevel) / 100.0,
+            present: camera_layer.is_some() as i32 as f32,
+        })
+    }
+
+    pub fn to_floats(self) -> [f32; CAMERA_FLOATS] {
+        let mut out = [0.0; CAMERA_FLOATS];
+        out[..16].copy_from_slice(&self.view);
+        out[16..32].copy_from_slice(&self.projection);
+        out[32] = self.zoom;
+        out[33] = self.focus_distance;
+        out[34] = self.aperture;
+        out[35] = self.blur_level;
+        out[36] = self.present;
+        out
+    }
+}
+
+impl Light {
+    // Every light in the effect layer's comp that is switched on,
+    // up to `MAX_LIGHTS`, in layer order.
+    pub fn collect_all(in_data: InData, time: FrameTime) -> Result<Vec<Self>, Error> {
+        let comp_time = comp_time(in_data, time)?;
+        let layer_suite = suites::Layer::new()?;
+        let light_suite = suites::Light::new()?;
+
+        let effect_layer = suites::PFInterface::new()?.effect_layer(in_data.effect())?;
+        let comp = layer_suite.layer_parent_comp(effect_layer)?;
+
+        let mut lights = Vec::new();
+        for i in 0..layer_suite.comp_num_layers(comp)? {
+            if lights.len() == MAX_LIGHTS {
+                break;
+            }
+
+            let layer = layer_suite.comp_layer_by_index(comp, i)?;
+            if layer_suite.layer_object_type(layer)? != ObjectType::Light
+                || !layer_suite.is_layer_video_really_on(layer)?
+            {
+                continue;
+            }
+
+            let kind = match light_suite.light_type(layer)? {
+                LightType::Parallel => LightKind::Parallel,
+                LightType::Spot => LightKind::Spot,
+                LightType::Point => LightKind::Point,
+                LightType::Ambient => LightKind::Ambient,
+                _ => continue,
+            };
+
+            // row vectors, the third row is the layer's z axis
+            // and the last its position.
+            let xform = layer_suite.layer_to_world_xform(layer, comp_time)?.mat;
+            let axis = [xform[2][0], xform[2][1], xform[2][2]];
+            let length = axis
+                .iter()
+                .map(|v| v * v)
+                .sum::<f64>()
+                .sqrt()
+                .max(f64::EPSILON);
+
+            lights.push(Light {
+                kind,
+                position: [xform[3][0], xform[3][1], xform[3][2]].map(|v| v as f32),
+                direction: axis.map(|v| (v / length) as f32),
+                color: stream_color(&layer, comp_time).unwrap_or([1.0; 3]),
+                intensity: stream_f64(&layer, LayerStream::Intensity, comp_time).unwrap_or(100.0)
+                    as f32
+                    / 100.0,
+                cone_angle: stream_f64(&layer, LayerStream::ConeAngle, comp_time).unwrap_or(0.0)
+                    as f32,
+            });
+        }
+
+        Ok(lights)
+    }
+
+    // position.w is the kind, direction.w the cone angle, color.a the intensity
+    fn to_floats(self) -> [f32; LIGHT_FLOATS] {
+        let [px, py, pz] = self.position;
+        let [dx, dy, dz] = self.direction;
+        let [r, g, b] = self.color;
+        [
+            px,
+            py,
+            pz,
+            self.kind as i32 as f32,
+            dx,
+            dy,
+            dz,
+            self.cone_angle,
+            r,
+            g,
+            b,
+            self.intensity,
+        ]
+    }
+}
+
+// The std140 `Light ae_lights[MAX_LIGHTS]` array, unused entries have kind 0
+pub fn lights_to_floats(lights: &[Light]) -> Vec<f32> {
+    let mut out = vec![0.0; MAX_LIGHTS * LIGHT_FLOATS];
+    for (chunk, light) in out.chunks_mut(LIGHT_FLOATS).zip(lights) {
+        chunk.copy_from_slice(&light.to_floats());
+    }
+    out
+}
+
+fn stream_value(
+    layer: &LayerHandle,
+    which: LayerStream,
+    time: Time,
+) -> Option<suites::StreamValue2> {
+    let plugin_id = *crate::PLUGIN_ID.get().unwrap_or(&10);
+    let stream_suite = suites::Stream::new().ok()?;
+    let stream = stream_suite
+        .new_layer_stream(plugin_id, *layer, which)
+        .ok()?;
+    stream_suite
+        .new_stream_value(stream, TimeMode::CompTime, time, false)
+        .ok()
+}
+
+fn stream_f64(layer: &LayerHandle, which: LayerStream, time: Time) -> Option<f64> {
+    stream_value(layer, which, time)?.as_f64()
+}
+
+fn stream_color(layer: &LayerHandle, time: Time) -> Option<[f32; 3]> {
+    let color = stream_value(layer, LayerStream::Color, time)?.as_color()?;
+    Some([color.red, color.green, color.blue].map(|v| v as f32))
+}
+
+// Inverse of a row vector affine transform, the camera's
+// world matrix becomes the view matrix.
+fn affine_inverse(m: &[[f64; 4]; 4]) -> [[f64; 4]; 4] {
+    let a = |r: usize, c: usize| m[r][c];
+
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        a(r0, c0) * a(r1, c1) - a(r0, c1) * a(r1, c0)
+    };
+
+    let det = (0..3).map(|c| a(0, c) * cofactor(0, c)).sum::<f64>();
+    if det.abs() < f64::EPSILON {
+        return *m;
+    }
+
+    let mut out = [[0.0; 4]; 4];
+    for (r, row) in out.iter_mut().enumerate().take(3) {
+        for (c, value) in row.iter_mut().enumerate().take(3) {
+            *value = cofactor(c, r) / det;
+        }
+    }
+
+    // -t * A^-1
+    for c in 0..3 {
+        out[3][c] = -(0..3).map(|k| m[3][k] * out[k][c]).sum::<f64>();
+    }
+    out[3][3] = 1.0;
+
+    out
+}
@@ -0,0 +1,102 @@
+// Fills the `date` utility block field, [year, month, day, seconds into the day].
+// Months and days start at 1, dates are in UTC.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+// `start` written as YYYYMMDD, advanced by `seconds` of comp time
+pub fn from_start_date(start: u32, seconds: f64) -> [f32; 4] {
+    let (year, month, day) = (start / 10_000, (start / 100) % 100, start % 100);
+    let start_days = days_from_civil(year as i64, month.clamp(1, 12), day.clamp(1, 31));
+    from_unix_seconds(start_days as f64 * SECONDS_PER_DAY + seconds)
+}
+
+pub fn wall_clock() -> [f32; 4] {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    from_unix_seconds(seconds)
+}
+
+fn from_unix_seconds(seconds: f64) -> [f32; 4] {
+    let days = (seconds / SECONDS_PER_DAY).floor();
+    let (year, month, day) = civil_from_days(days as i64);
+    [
+        year as f32,
+        month as f32,
+        day as f32,
+        (seconds - days * SECONDS_PER_DAY) as f32,
+    ]
+}
+
+// Days since 1970-01-01 in the proleptic gregorian calendar,
+// from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_round_trip_through_civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+
+        for days in [-800_000, -1, 0, 59, 10_957, 20_000, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn start_date_advances_by_comp_seconds() {
+        assert_eq!(from_start_date(20_000_101, 0.0), [2000.0, 1.0, 1.0, 0.0]);
+        assert_eq!(from_start_date(20_000_101, 90.0), [2000.0, 1.0, 1.0, 90.0]);
+        assert_eq!(
+            from_start_date(19_991_231, SECONDS_PER_DAY + 30.0),
+            [2000.0, 1.0, 1.0, 30.0]
+        );
+        assert_eq!(
+            from_start_date(20_240_228, SECONDS_PER_DAY),
+            [2024.0, 2.0, 29.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn start_date_clamps_invalid_months_and_days() {
+        assert_eq!(
+            from_start_date(20_001_399, 0.0),
+            from_start_date(20_001_231, 0.0)
+        );
+        assert_eq!(
+            from_start_date(20_000_000, 0.0),
+            from_start_date(20_000_101, 0.0)
+        );
+    }
+}
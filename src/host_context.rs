@@ -0,0 +1,93 @@
+// Facts about the comp and the effect's layer for the `ae_host` uniform,
+// read through the AEGP suites. Times are in comp seconds.
+
+use crate::host_util::{column_major, comp_time, seconds};
+use crate::types::FrameTime;
+use after_effects::aegp::{suites, LayerStream, TimeMode};
+use after_effects::{Error, InData};
+
+// std140 layout of `ae_host`, see `host_uniforms::HOST_CONTEXT`
+pub const FLOAT_COUNT: usize = 25;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostContext {
+    // column major
+    pub layer_to_comp: [f32; 16],
+    pub comp_size: [f32; 2],
+    // start, end
+    pub work_area: [f32; 2],
+    // in point, out point
+    pub layer_in_out: [f32; 2],
+    pub comp_frame_rate: f32,
+    pub comp_duration: f32,
+    // -1 when no layer marker is at or before the current time
+    pub time_since_marker: f32,
+}
+
+impl HostContext {
+    pub fn collect(in_data: InData, time: FrameTime) -> Result<Self, Error> {
+        let plugin_id = *crate::PLUGIN_ID.get().unwrap_or(&10);
+
+        let layer = suites::PFInterface::new()?.effect_layer(in_data.effect())?;
+        let layer_suite = suites::Layer::new()?;
+        let comp_suite = suites::Comp::new()?;
+        let item_suite = suites::Item::new()?;
+
+        let comp = layer_suite.layer_parent_comp(layer)?;
+        let comp_item = comp_suite.item_from_comp(comp)?;
+
+        let now = comp_time(in_data, time)?;
+
+        let (width, height) = item_suite.item_dimensions(comp_item)?;
+        let work_start = comp_suite.comp_work_area_start(comp)?;
+        let work_duration = comp_suite.comp_work_area_duration(comp)?;
+        let in_point = layer_suite.layer_in_point(layer, TimeMode::CompTime)?;
+        let layer_duration = layer_suite.layer_duration(layer, TimeMode::CompTime)?;
+
+        let layer_to_comp = column_major(&layer_suite.layer_to_world_xform(layer, now)?.mat);
+
+        // layer markers are keyframes on the marker stream
+        let marker_stream =
+            suites::Stream::new()?.new_layer_stream(plugin_id, layer, LayerStream::Marker)?;
+        let keyframe_suite = suites::Keyframe::new()?;
+        let last_marker = (0..keyframe_suite.stream_num_kfs(marker_stream)?)
+            .filter_map(|i| {
+                keyframe_suite
+                    .keyframe_time(marker_stream, i, TimeMode::CompTime)
+                    .ok()
+            })
+            .map(seconds)
+            .filter(|t| *t <= seconds(now))
+            .reduce(f64::max);
+
+        Ok(Self {
+            layer_to_comp,
+            comp_size: [width as f32, height as f32],
+            work_area: [
+                seconds(work_start) as f32,
+                (seconds(work_start) + seconds(work_duration)) as f32,
+            ],
+            layer_in_out: [
+                seconds(in_point) as f32,
+                (seconds(in_point) + seconds(layer_duration)) as f32,
+            ],
+            comp_frame_rate: comp_suite.comp_framerate(comp)? as f32,
+            comp_duration: seconds(item_suite.item_duration(comp_item)?) as f32,
+            time_since_marker: last_marker
+                .map(|marker| (seconds(now) - marker) as f32)
+                .unwrap_or(-1.0),
+        })
+    }
+
+    pub fn to_floats(self) -> [f32; FLOAT_COUNT] {
+        let mut out = [0.0; FLOAT_COUNT];
+        out[..16].copy_from_slice(&self.layer_to_comp);
+        out[16..18].copy_from_slice(&self.comp_size);
+        out[18..20].copy_from_slice(&self.work_area);
+        out[20..22].copy_from_slice(&self.layer_in_out);
+        out[22] = self.comp_frame_rate;
+        out[23] = self.comp_duration;
+        out[24] = self.time_since_marker;
+        out
+    }
+}
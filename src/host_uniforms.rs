@@ -0,0 +1,161 @@
+// Uniform fields named with the `ae_` prefix that have no input pragma
+// are not given a parameter, tweak_shader exposes them to us as raw bytes
+// and the plugin fills them in every frame with facts from the host.
+
+use crate::types::InputPlacement;
+use tweak_shader::RenderContext;
+
+pub const PREFIX: &str = "ae_";
+
+// vec4, pixels added around the layer [left, top, right, bottom].
+// the layer's top left corner sits at `ae_expansion.xy`.
+pub const EXPANSION: &str = "ae_expansion";
+
+// vec2, the fraction of full resolution AE is rendering at, [x, y].
+// 0.5 at half resolution preview.
+pub const DOWNSAMPLE: &str = "ae_downsample";
+
+// float, the layer's pixel aspect ratio, width over height
+pub const PIXEL_ASPECT: &str = "ae_pixel_aspect";
+
+// float, 1.0 at best quality, 0.0 at draft quality
+pub const QUALITY: &str = "ae_quality";
+
+// float, 1.0 for an interactive preview, 0.0 for a final
+// render and -1.0 when the host does not say.
+pub const PREVIEW: &str = "ae_preview";
+
+// struct, facts about the comp and the effect's layer, laid out as
+// struct HostContext {
+//     mat4 layer_to_comp;      // layer pixels to comp pixels
+//     vec2 comp_size;          // pixels
+//     vec2 work_area;          // start, end
+//     vec2 layer_in_out;       // in point, out point
+//     float comp_frame_rate;
+//     float comp_duration;
+//     float time_since_marker; // -1.0 before the first layer marker
+// };
+// times are in comp seconds.
+pub const HOST_CONTEXT: &str = "ae_host";
+
+// struct, the comp's active camera, laid out as
+// struct Camera {
+//     mat4 view;            // comp pixels to camera space
+//     mat4 projection;      // camera space to comp pixels, divide by w
+//     float zoom;           // pixels
+//     float focus_distance; // pixels
+//     float aperture;       // pixels
+//     float blur_level;     // 0 to 1
+//     float present;        // 1.0 if the comp has a camera layer
+// };
+// without a camera layer AE's default camera is used.
+pub const CAMERA: &str = "ae_camera";
+
+// array of `camera::MAX_LIGHTS` structs, the comp's lights laid out as
+// struct Light {
+//     vec4 position;  // xyz comp pixels, w type
+//     vec4 direction; // xyz unit vector, w cone angle in degrees
+//     vec4 color;     // rgb, a intensity, 1.0 at 100%
+// };
+// types are 1 parallel, 2 spot, 3 point and 4 ambient,
+// unused entries are 0.
+pub const LIGHTS: &str = "ae_lights";
+
+// float, how many entries of `ae_lights` are used
+pub const LIGHT_COUNT: &str = "ae_light_count";
+
+// vec4, where an image input sits in output pixels [x, y, width, height].
+// named `ae_<input name>_rect`.
+pub fn input_rect_name(input: &str) -> String {
+    format!("{PREFIX}{input}_rect")
+}
+
+// struct, named `ae_<input name>_info`, laid out as
+// struct LayerInfo {
+//     vec2 size;           // pixels
+//     float aspect;        // pixel aspect ratio
+//     float present;       // 1.0 if a layer is connected
+//     float time_offset;   // seconds relative to the current time
+//     float premultiplied; // 1.0 if color is premultiplied by alpha
+// };
+pub fn input_info_name(input: &str) -> String {
+    format!("{PREFIX}{input}_info")
+}
+
+// Fills the rect and info uniforms of an image input, `placement` is None
+// when no layer is bound and `present` false when the bound layer only
+// stands in for an empty layer param. The rect is multiplied by `scale`,
+// the supersampling factor, so it stays in the pixels the shader renders.
+pub fn write_input_info(
+    ctx: &mut RenderContext,
+    input: &str,
+    placement: Option<&InputPlacement>,
+    present: bool,
+    scale: f32,
+) {
+    let Some(placement) = placement else {
+        write_f32s(ctx, &input_rect_name(input), &[0.0; 4]);
+        write_f32s(ctx, &input_info_name(input), &[0.0; 6]);
+        return;
+    };
+
+    let [x, y] = placement.origin.map(|v| v as f32);
+    let [width, height] = placement.size.map(|v| v as f32);
+
+    write_f32s(
+        ctx,
+        &input_rect_name(input),
+        &[x, y, width, height].map(|v| v * scale),
+    );
+    write_f32s(
+        ctx,
+        &input_info_name(input),
+        &[
+            width,
+            height,
+            placement.pixel_aspect,
+            present as i32 as f32,
+            placement.time_offset,
+            // AE effect worlds hold straight alpha
+            0.0,
+        ],
+    );
+}
+
+// struct, named `ae_<input name>_levels` for audio inputs, laid out as
+// struct AudioLevels {
+//     vec4 bands; // energy below 250hz, 2khz, 6khz and above
+//     float rms;
+// };
+// averaged over the channels.
+pub fn audio_levels_name(input: &str) -> String {
+    format!("{PREFIX}{input}_levels")
+}
+
+// True if the shader declared the named uniform, for
+// values that are expensive to gather.
+pub fn is_declared(ctx: &RenderContext, name: &str) -> bool {
+    ctx.iter_inputs().any(|(input, _)| input == name)
+}
+
+pub fn is_host_uniform(name: &str) -> bool {
+    name.starts_with(PREFIX)
+}
+
+// Copies `bytes` into the named uniform, silently skipping
+// fields the shader did not declare.
+pub fn write_bytes(ctx: &mut RenderContext, name: &str, bytes: &[u8]) {
+    let Some(mut input) = ctx.get_input_mut(name) else {
+        return;
+    };
+
+    if let Some(raw) = input.as_raw_bytes() {
+        let len = raw.inner.len().min(bytes.len());
+        raw.inner[..len].copy_from_slice(&bytes[..len]);
+    }
+}
+
+pub fn write_f32s(ctx: &mut RenderContext, name: &str, values: &[f32]) {
+    let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_ne_bytes()).collect();
+    write_bytes(ctx, name, &bytes);
+}
@@ -0,0 +1,34 @@
+// Conversions shared by everything that reads the comp through the AEGP suites
+
+use crate::types::FrameTime;
+use after_effects::aegp::suites;
+use after_effects::{Error, InData, Time};
+
+// `time` on the effect's layer in comp time, the layer's
+// start time and time stretch applied.
+pub fn comp_time(in_data: InData, time: FrameTime) -> Result<Time, Error> {
+    let layer = suites::PFInterface::new()?.effect_layer(in_data.effect())?;
+    suites::Layer::new()?.convert_layer_to_comp_time(
+        layer,
+        Time {
+            value: time.time,
+            scale: time.scale,
+        },
+    )
+}
+
+pub fn seconds(time: Time) -> f64 {
+    time.value as f64 / time.scale.max(1) as f64
+}
+
+// AE matrices multiply row vectors, so their rows are the columns of the
+// same transform for column vectors and can be written out in order.
+pub fn column_major(mat: &[[f64; 4]; 4]) -> [f32; 16] {
+    let mut out = [0.0; 16];
+    for (i, row) in mat.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            out[i * 4 + j] = *value as f32;
+        }
+    }
+    out
+}
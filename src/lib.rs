@@ -1,6 +1,18 @@
+mod annotations;
+mod audio;
+mod camera;
+mod date;
+mod host_context;
+mod host_uniforms;
+mod host_util;
+mod motion_blur;
 mod param_util;
 mod preprocessing;
 mod render;
+mod simulation;
+mod supersample;
+mod texture_util;
+mod time_controls;
 mod types;
 mod window_handle;
 
@@ -13,8 +25,24 @@ use after_effects_sys as ae_sys;
 use types::*;
 
 const SERDE_ID_V1: u16 = 1;
-const SERDE_ID: u16 = 2;
+const SERDE_ID_V2: u16 = 2;
+const SERDE_ID: u16 = 3;
 const INPUT_LAYER_CHECKOUT_ID: ParamIdx = ParamIdx::Dynamic(240);
+
+// Checkout id for the effect's own layer sampled at an image input's time offset
+const fn main_layer_checkout_id(input: ParamIdx) -> i32 {
+    INPUT_LAYER_CHECKOUT_ID.idx() + 1 + input.idx()
+}
+
+// Every checkout made for a motion blur sub frame is the id it would have at
+// the current time, offset into a block reserved for that sub frame.
+const SUB_FRAME_CHECKOUT_BASE: i32 = 1024;
+const SUB_FRAME_CHECKOUT_STRIDE: i32 = 1024;
+
+const fn sub_frame_checkout_id(sub_frame: usize, id: i32) -> i32 {
+    SUB_FRAME_CHECKOUT_BASE + sub_frame as i32 * SUB_FRAME_CHECKOUT_STRIDE + id
+}
+
 static PLUGIN_ID: std::sync::OnceLock<i32> = std::sync::OnceLock::new();
 
 ae::define_effect!(TweakShaderGlobal, LocalMutex, ParamIdx);
@@ -28,7 +56,7 @@ macro_rules! lock {
 impl AdobePluginInstance for LocalMutex {
     fn flatten(&self) -> Result<(u16, Vec<u8>), Error> {
         let locked = lock!(self);
-        let data = (&locked.src, &locked.src_path);
+        let data = (&locked.src, &locked.src_path, &locked.param_layout);
         let out = bincode::serialize(&data).map_err(|_| Error::Generic)?;
         Ok((SERDE_ID, out))
     }
@@ -36,12 +64,28 @@ impl AdobePluginInstance for LocalMutex {
     fn unflatten(version: u16, serialized: &[u8]) -> Result<Self, Error> {
         match version {
             SERDE_ID => {
+                let (src, src_path, param_layout): (
+                    Option<String>,
+                    Option<std::path::PathBuf>,
+                    ParamLayout,
+                ) = bincode::deserialize(serialized).map_err(|_| Error::Generic)?;
+                let mut out = Local::default();
+                out.local_init = None;
+                out.src = src;
+                out.src_path = src_path;
+                out.param_layout = param_layout;
+                Ok(Mutex::new(out))
+            }
+            // older projects keep their values and keyframes
+            // in the params every input used to own.
+            SERDE_ID_V2 => {
                 let (src, src_path): (Option<String>, Option<std::path::PathBuf>) =
                     bincode::deserialize(serialized).map_err(|_| Error::Generic)?;
                 let mut out = Local::default();
                 out.local_init = None;
                 out.src = src;
                 out.src_path = src_path;
+                out.param_layout = ParamLayout::Legacy;
                 Ok(Mutex::new(out))
             }
             SERDE_ID_V1 => {
@@ -51,6 +95,7 @@ impl AdobePluginInstance for LocalMutex {
                 out.local_init = None;
                 out.src = src;
                 out.src_path = None;
+                out.param_layout = ParamLayout::Legacy;
                 Ok(Mutex::new(out))
             }
             _ => Err(Error::Generic),
@@ -67,9 +112,7 @@ impl AdobePluginInstance for LocalMutex {
     }
 
     fn handle_command(&mut self, plugin: &mut PluginState, command: Command) -> Result<(), Error> {
-        let PluginState {
-            out_data, in_data, ..
-        } = plugin;
+        let PluginState { out_data, .. } = plugin;
         match command {
             Command::About => {
                 out_data.set_return_msg("Tweak Shader, v2.0, The flexible shader plugin.")
@@ -79,7 +122,14 @@ impl AdobePluginInstance for LocalMutex {
                 param_util::update_param_ui(plugin, &mut lock!(self))?;
             }
             Command::UserChangedParam { param_index } => {
-                match ParamIdx::from(param_index as u8) {
+                // checkpoints were simulated with the old values, inputs are
+                // checked against the values hashed for each frame instead
+                let is_input = matches!(ParamIdx::from(param_index as u16), ParamIdx::Dynamic(_));
+                if let Some(init) = lock!(self).local_init.as_mut().filter(|_| !is_input) {
+                    init.simulation.invalidate();
+                }
+
+                match ParamIdx::from(param_index as u16) {
                     ParamIdx::UnloadButton => {
                         lock!(self).unload_scene();
                         param_util::update_param_defaults_and_labels(plugin, &mut lock!(self))?;
@@ -102,6 +152,12 @@ impl AdobePluginInstance for LocalMutex {
                             param_util::update_param_defaults_and_labels(plugin, &mut lock!(self))?;
                         }
                     }
+                    ParamIdx::TimeSource => {
+                        // the popup takes over from the older Use Layer Time checkbox
+                        let mut param = plugin.params.get_mut(ParamIdx::UseLayerTime)?;
+                        param.as_checkbox_mut()?.set_value(true);
+                        param.set_value_changed();
+                    }
                     ParamIdx::IsImageFilter => {
                         if let Some(init) = lock!(self).local_init.as_mut() {
                             init.queue_param_visibility_reset();
@@ -117,11 +173,11 @@ impl AdobePluginInstance for LocalMutex {
                             .local_init
                             .as_ref()
                             .and_then(|init| {
-                                init.ctx
-                                    .iter_inputs()
-                                    .enumerate()
-                                    .find(|(_, (_, i))| i.is_stored_as_texture())
-                                    .map(|(i, (_, ty))| param_util::as_param_index(i, ty))
+                                param_util::first_image_slot(
+                                    &init.ctx,
+                                    &init.annotations,
+                                    init.param_layout,
+                                )
                             })
                             .clone();
 
@@ -139,74 +195,34 @@ impl AdobePluginInstance for LocalMutex {
                             )?;
                         }
                     }
+                    // inputs may show or hide with the value of another
+                    ParamIdx::Dynamic(_) => {
+                        param_util::update_conditional_params(plugin, &mut lock!(self))?;
+                    }
                     _ => {}
                 }
                 plugin.out_data.set_force_rerender();
             }
+            Command::QueryDynamicFlags => {
+                let local = lock!(self);
+                out_data.set_out_flag(OutFlags::NonParamVary, local.is_time_dependent());
+                // sub frames are checked out between frames
+                let may_blur = render::may_motion_blur(&local, plugin.in_data)?;
+                out_data.set_out_flag(
+                    OutFlags::WideTimeInput,
+                    local.reads_other_times() || may_blur,
+                );
+                out_data.set_out_flag2(
+                    OutFlags2::IUse3DCamera,
+                    local.declares(host_uniforms::CAMERA),
+                );
+                out_data.set_out_flag2(
+                    OutFlags2::IUse3DLights,
+                    local.declares(host_uniforms::LIGHTS),
+                );
+            }
             Command::SmartPreRender { mut extra } => {
-                let mut req = extra.output_request();
-
-                let cb = extra.callbacks();
-
-                if let Some(global) = plugin.global.as_init() {
-                    lock!(self).init_or_update(
-                        &global.device,
-                        &global.queue,
-                        extra.bit_depth().into(),
-                    );
-
-                    let current_time = in_data.current_time();
-                    let time_step = in_data.time_step();
-                    let time_scale = in_data.time_scale();
-
-                    if let Some(LocalInit { ctx, .. }) = lock!(self).local_init.as_ref() {
-                        for (index, (_, v)) in ctx
-                            .iter_inputs()
-                            .enumerate()
-                            .filter(|(_, (_, v))| v.is_stored_as_texture())
-                        {
-                            let id_and_index = param_util::as_param_index(index, v).idx();
-
-                            cb.checkout_layer(
-                                id_and_index,
-                                id_and_index,
-                                &req,
-                                current_time,
-                                time_step,
-                                time_scale,
-                            )?;
-                        }
-                    }
-                }
-
-                req.field = ae_sys::PF_Field_FRAME as i32;
-                req.preserve_rgb_of_zero_alpha = 1;
-                req.channel_mask = ae_sys::PF_ChannelMask_ARGB as i32;
-
-                // We checkout once just to see what the max rect is :(
-                if let Ok(width_test) = cb.checkout_layer(
-                    0,
-                    INPUT_LAYER_CHECKOUT_ID.idx() - 1,
-                    &req,
-                    in_data.current_time(),
-                    in_data.time_step(),
-                    in_data.time_scale(),
-                ) {
-                    req.rect = width_test.max_result_rect;
-
-                    let full_checkout = cb.checkout_layer(
-                        0,
-                        INPUT_LAYER_CHECKOUT_ID.idx(),
-                        &req,
-                        in_data.current_time(),
-                        in_data.time_step(),
-                        in_data.time_scale(),
-                    )?;
-
-                    extra.set_result_rect(full_checkout.result_rect.into());
-                    extra.set_max_result_rect(full_checkout.result_rect.into());
-                    extra.set_returns_extra_pixels(true);
-                }
+                render::pre_render(plugin, &mut lock!(self), &mut extra)?;
             }
             Command::SmartRender { extra } => {
                 render::render(plugin, &mut lock!(self), &extra)?;
@@ -241,6 +257,10 @@ impl AdobePluginGlobal for TweakShaderGlobal {
     ) -> Result<(), Error> {
         param_util::setup_static_params(params)?;
         param_util::create_variant_backing(params)?;
+        param_util::setup_extended_params(params)?;
+        param_util::create_opacity_backing(params)?;
+        param_util::create_vector_backing(params)?;
+        param_util::create_angle_backing(params)?;
         Ok(())
     }
 
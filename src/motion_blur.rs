@@ -0,0 +1,167 @@
+use crate::texture_util::target_desc;
+use tweak_shader::{
+    wgpu::{self, Device, Queue},
+    RenderContext,
+};
+
+// Accumulates renders of the main shader at several sub frame times
+// into a float texture, then resolves it into the output format.
+#[derive(Debug)]
+pub struct MotionBlurContext {
+    accumulate_ctx: RenderContext,
+    // converts the accumulated result into the main context's format
+    resolve_ctx: RenderContext,
+    fmt: wgpu::TextureFormat,
+    // the main shader renders each sub frame here when it is not supersampled
+    sub_frame_texture: Option<wgpu::Texture>,
+    // ping ponged, `current` holds the running sum
+    accum_textures: Option<[wgpu::Texture; 2]>,
+    current: usize,
+    samples_taken: usize,
+}
+
+impl MotionBlurContext {
+    pub fn new(device: &Device, queue: &Queue, fmt: wgpu::TextureFormat) -> Self {
+        Self {
+            accumulate_ctx: RenderContext::new(
+                include_str!("./resources/accumulate.glsl"),
+                wgpu::TextureFormat::Rgba32Float,
+                device,
+                queue,
+            )
+            .expect("accumulation context broken"),
+            resolve_ctx: RenderContext::new(
+                include_str!("./resources/resolve.glsl"),
+                fmt,
+                device,
+                queue,
+            )
+            .expect("resolve context broken"),
+            fmt,
+            sub_frame_texture: None,
+            accum_textures: None,
+            current: 0,
+            samples_taken: 0,
+        }
+    }
+
+    // Starts a new sum, reallocating targets if the output size changed
+    pub fn begin(&mut self, device: &Device, width: u32, height: u32) {
+        let fits = |t: &wgpu::Texture| t.width() == width && t.height() == height;
+
+        if !self.sub_frame_texture.as_ref().is_some_and(fits) {
+            self.sub_frame_texture =
+                Some(device.create_texture(&target_desc(width, height, self.fmt)));
+        }
+
+        if !self.accum_textures.as_ref().is_some_and(|[a, _]| fits(a)) {
+            self.accum_textures = Some([
+                device.create_texture(&target_desc(
+                    width,
+                    height,
+                    wgpu::TextureFormat::Rgba32Float,
+                )),
+                device.create_texture(&target_desc(
+                    width,
+                    height,
+                    wgpu::TextureFormat::Rgba32Float,
+                )),
+            ]);
+        }
+
+        self.current = 0;
+        self.samples_taken = 0;
+    }
+
+    // Has `render_sub_frame` fill the sub frame texture and adds it to the sum
+    pub fn accumulate_render(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        weight: f32,
+        render_sub_frame: impl FnOnce(&wgpu::Texture),
+    ) {
+        let Some(sub_frame) = self.sub_frame_texture.take() else {
+            return;
+        };
+
+        render_sub_frame(&sub_frame);
+        self.accumulate(device, queue, weight, &sub_frame);
+
+        self.sub_frame_texture = Some(sub_frame);
+    }
+
+    // Adds an output sized sub frame in any float readable format to the sum
+    pub fn accumulate(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        weight: f32,
+        sub_frame: &wgpu::Texture,
+    ) {
+        let Some(accum) = self.accum_textures.as_ref() else {
+            return;
+        };
+
+        let width = sub_frame.width();
+        let height = sub_frame.height();
+
+        let keep = if self.samples_taken == 0 { 0.0 } else { 1.0 };
+        set_float(&mut self.accumulate_ctx, "weight", weight);
+        set_float(&mut self.accumulate_ctx, "keep", keep);
+
+        let previous = &accum[self.current];
+        let next = &accum[1 - self.current];
+
+        self.accumulate_ctx
+            .load_shared_texture(sub_frame, "sub_frame");
+        self.accumulate_ctx.load_shared_texture(previous, "accum");
+
+        let mut enc = device.create_command_encoder(&Default::default());
+        self.accumulate_ctx.render(
+            queue,
+            device,
+            &mut enc,
+            next.create_view(&Default::default()),
+            width,
+            height,
+        );
+        queue.submit([enc.finish()]);
+
+        self.current = 1 - self.current;
+        self.samples_taken += 1;
+    }
+
+    // The running sum, in Rgba32Float
+    pub fn result(&self) -> Option<&wgpu::Texture> {
+        self.accum_textures.as_ref().map(|a| &a[self.current])
+    }
+
+    // Writes the sum to a CPU buffer in the main context's format
+    pub fn resolve_to_slice(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        out: &mut [u8],
+        stride: u32,
+    ) {
+        let Some(result) = self.accum_textures.as_ref().map(|a| &a[self.current]) else {
+            return;
+        };
+
+        let width = result.width();
+        let height = result.height();
+
+        self.resolve_ctx.load_shared_texture(result, "input_image");
+        self.resolve_ctx
+            .render_to_slice(queue, device, width, height, out, Some(stride));
+    }
+}
+
+fn set_float(ctx: &mut RenderContext, name: &str, value: f32) {
+    if let Some(mut input) = ctx.get_input_mut(name) {
+        if let Some(float) = input.as_float() {
+            float.current = value;
+        }
+    }
+}
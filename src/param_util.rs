@@ -1,17 +1,61 @@
-use crate::types::ParamIdx;
-use ae::aegp::DynamicStreamFlags;
+use crate::annotations::{Annotations, FloatUi, VectorKind};
+use crate::host_uniforms;
+use crate::types::{ParamIdx, ParamLayout};
+use ae::aegp::{DynamicStreamFlags, StreamValue};
 use ae::ParamFlag;
 use after_effects as ae;
 use after_effects::aegp::suites;
 use after_effects::{Error, InData};
-use after_effects_sys::PF_Pixel;
 use tweak_shader::input_type::InputType;
+use tweak_shader::RenderContext;
 
 pub const MAX_INPUTS: i32 = 32;
 pub const PARAM_TYPE_COUNT: i32 = 7;
 pub const STATIC_PARAMS_OFFSET: i32 = ParamIdx::UseLayerTime.idx() + 1;
 pub const PARAM_COUNT: i32 = (PARAM_TYPE_COUNT * MAX_INPUTS) + STATIC_PARAMS_OFFSET;
+// params added after the variant backing, new static params go
+// here so the dynamic layout of existing projects does not shift.
+pub const EXTENDED_PARAMS: [ParamIdx; 14] = [
+    ParamIdx::ExpandPixels,
+    ParamIdx::MotionBlurSamples,
+    ParamIdx::ShutterAngle,
+    ParamIdx::UseCompShutter,
+    ParamIdx::Supersampling,
+    ParamIdx::SupersampleFilter,
+    ParamIdx::DateSource,
+    ParamIdx::StartDate,
+    ParamIdx::Mouse,
+    ParamIdx::MouseDown,
+    ParamIdx::TimeSource,
+    ParamIdx::TimeOffset,
+    ParamIdx::PlaybackSpeed,
+    ParamIdx::LoopDuration,
+];
+// The order of a group's params, one of each `Variant`
+pub const VARIANTS: [Variant; PARAM_TYPE_COUNT as usize] = [
+    Variant::Float,
+    Variant::Int,
+    Variant::IntList,
+    Variant::Point,
+    Variant::Bool,
+    Variant::Color,
+    Variant::Image,
+];
+// an opacity slider paired with each group's color, see `group_of`.
+pub const OPACITY_OFFSET: i32 = PARAM_COUNT + EXTENDED_PARAMS.len() as i32;
+pub const OPACITY_COUNT: i32 = MAX_INPUTS;
+// the nth vector input owns the nth group of `VECTOR_PARAMS` params,
+// a slider per component followed by a 3D point.
+pub const VECTOR_OFFSET: i32 = OPACITY_OFFSET + OPACITY_COUNT;
+pub const MAX_VECTORS: i32 = 8;
+pub const VECTOR_PARAMS: i32 = 5;
+// an angle param paired with each group's float, shown in place of
+// the slider of `ui=angle` inputs, like the opacity sliders.
+pub const ANGLE_OFFSET: i32 = VECTOR_OFFSET + (MAX_VECTORS * VECTOR_PARAMS);
+pub const ANGLE_COUNT: i32 = MAX_INPUTS;
+pub const TOTAL_PARAM_COUNT: i32 = ANGLE_OFFSET + ANGLE_COUNT;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Variant {
     Float = 0,
     Int,
@@ -22,38 +66,325 @@ pub enum Variant {
     Image,
 }
 
-pub fn index_from_mut(index: usize, variant: &mut tweak_shader::input_type::MutInput) -> ParamIdx {
-    let variant = match variant.variant() {
-        tweak_shader::input_type::InputVariant::Float => Variant::Float as _,
-        tweak_shader::input_type::InputVariant::Int
-            if variant.as_int().is_some_and(|e| e.labels.is_none()) =>
-        {
-            Variant::Int as _
+impl Variant {
+    // The param backing an input, None for inputs AE has no param for.
+    // Those are rejected when the shader loads, see `check_inputs`.
+    pub fn of(ty: &InputType) -> Option<Self> {
+        Some(match ty {
+            InputType::Float(_) => Variant::Float,
+            InputType::Int(_, None) => Variant::Int,
+            InputType::Int(_, Some(_)) => Variant::IntList,
+            InputType::Point(_) => Variant::Point,
+            // events fire while the checkbox is checked
+            InputType::Bool(_) | InputType::Event(_) => Variant::Bool,
+            InputType::Color(_) => Variant::Color,
+            // audio is checked out from a layer
+            InputType::Image(_) | InputType::Audio(..) | InputType::AudioFft(..) => Variant::Image,
+            InputType::RawBytes(_) => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Variant::Float => "float",
+            Variant::Int => "int",
+            Variant::IntList => "labeled int",
+            Variant::Point => "point",
+            Variant::Bool => "bool and event",
+            Variant::Color => "color",
+            Variant::Image => "image and audio",
         }
-        tweak_shader::input_type::InputVariant::Int => Variant::IntList as _,
-        tweak_shader::input_type::InputVariant::Point => Variant::Point as _,
-        tweak_shader::input_type::InputVariant::Bool => Variant::Bool as _,
-        tweak_shader::input_type::InputVariant::Color => Variant::Color as _,
-        tweak_shader::input_type::InputVariant::Image => Variant::Image as _,
-        _ => 0,
+    }
+}
+
+// The param backing each input, in `iter_inputs` order. Params come in
+// `MAX_INPUTS` groups of one param of each type. In the legacy layout the
+// nth input owns the nth group, in the pooled layout the nth input of a
+// type in `display_order` takes its type's param of the nth group, so
+// groups are shared by inputs of different types. None for host uniforms,
+// unsupported inputs and inputs past the limits.
+pub fn input_slots(
+    ctx: &RenderContext,
+    annotations: &Annotations,
+    layout: ParamLayout,
+) -> Vec<Option<ParamIdx>> {
+    let inputs: Vec<_> = ctx.iter_inputs().collect();
+    let mut slots = vec![None; inputs.len()];
+    let mut used = [0; PARAM_TYPE_COUNT as usize];
+
+    let order = match layout {
+        // existing projects keep their keyframes where they were
+        ParamLayout::Legacy => (0..inputs.len()).collect(),
+        ParamLayout::Pooled => display_order(ctx, annotations),
     };
 
-    ParamIdx::Dynamic(((index as i32 * PARAM_TYPE_COUNT) + STATIC_PARAMS_OFFSET + variant) as u8)
+    for i in order {
+        let (name, ty) = inputs[i];
+        if host_uniforms::is_host_uniform(name) {
+            continue;
+        }
+
+        let Some(variant) = Variant::of(ty) else {
+            continue;
+        };
+
+        let group = match layout {
+            ParamLayout::Legacy => i as i32,
+            ParamLayout::Pooled => {
+                used[variant as usize] += 1;
+                used[variant as usize] - 1
+            }
+        };
+
+        if group >= MAX_INPUTS {
+            continue;
+        }
+
+        let index = (group * PARAM_TYPE_COUNT) + STATIC_PARAMS_OFFSET + variant as i32;
+        slots[i] = Some(ParamIdx::Dynamic(index as u16));
+    }
+
+    slots
 }
 
-pub fn as_param_index(index: usize, variant: &tweak_shader::input_type::InputType) -> ParamIdx {
-    let variant = match variant {
-        tweak_shader::input_type::InputType::Float(_) => Variant::Float as _,
-        tweak_shader::input_type::InputType::Int(_, None) => Variant::Int as _,
-        tweak_shader::input_type::InputType::Int(_, Some(_)) => Variant::IntList as _,
-        tweak_shader::input_type::InputType::Point(_) => Variant::Point as _,
-        tweak_shader::input_type::InputType::Bool(_) => Variant::Bool as _,
-        tweak_shader::input_type::InputType::Color(_) => Variant::Color as _,
-        tweak_shader::input_type::InputType::Image(_) => Variant::Image as _,
-        _ => 0,
+// Positions in `iter_inputs` sorted for display. Ungrouped inputs come
+// first, then each group in the order it first appears. Within a group
+// inputs with an `order` come first, lowest first, then the rest as declared.
+// Params are grouped across types, so this only orders inputs of the same type.
+fn display_order(ctx: &RenderContext, annotations: &Annotations) -> Vec<usize> {
+    let mut groups: Vec<&str> = Vec::new();
+    let mut keys: Vec<(usize, i64, usize)> = ctx
+        .iter_inputs()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            let input = annotations.input(name);
+            let group = match input.and_then(|a| a.group.as_deref()) {
+                Some(group) => match groups.iter().position(|g| *g == group) {
+                    Some(rank) => rank + 1,
+                    None => {
+                        groups.push(group);
+                        groups.len()
+                    }
+                },
+                None => 0,
+            };
+            let order = input
+                .and_then(|a| a.order)
+                .map_or(i64::MAX, |order| order as i64);
+            (group, order, i)
+        })
+        .collect();
+
+    keys.sort();
+    keys.into_iter().map(|(_, _, i)| i).collect()
+}
+
+// AE cuts param names past this many characters
+const MAX_PARAM_NAME_LEN: usize = 31;
+
+// The name an input's params are shown under, its label or its
+// uniform's name, prefixed by its group and cut to AE's limit.
+fn display_name(annotations: &Annotations, name: &str) -> String {
+    let input = annotations.input(name);
+    let label = input.and_then(|a| a.label.as_deref()).unwrap_or(name);
+    let name = match input.and_then(|a| a.group.as_deref()) {
+        Some(group) => format!("{group} / {label}"),
+        None => label.to_owned(),
     };
 
-    ParamIdx::Dynamic(((index as i32 * PARAM_TYPE_COUNT) + STATIC_PARAMS_OFFSET + variant) as u8)
+    name.chars().take(MAX_PARAM_NAME_LEN).collect()
+}
+
+// True if a `visible_if` or `enabled_if` condition reads the input. Only
+// those params are supervised, changes to the rest need no UI update and
+// would otherwise discard simulation checkpoints on every edit.
+fn is_condition_input(annotations: &Annotations, name: &str) -> bool {
+    annotations
+        .inputs
+        .values()
+        .flat_map(|a| a.conditions())
+        .any(|c| c.inputs().any(|input| input == name))
+}
+
+// True if the pragma asked for the input's params to stay hidden
+fn is_hidden(annotations: &Annotations, name: &str) -> bool {
+    annotations.input(name).is_some_and(|a| a.hidden)
+}
+
+// The group an input's param belongs to, see `input_slots`
+fn group_of(slot: ParamIdx) -> i32 {
+    (slot.idx() - STATIC_PARAMS_OFFSET) / PARAM_TYPE_COUNT
+}
+
+// The opacity slider paired with a color input's param
+pub fn opacity_slot(color: ParamIdx) -> ParamIdx {
+    ParamIdx::Dynamic((OPACITY_OFFSET + group_of(color)) as u16)
+}
+
+// The angle param paired with a float input's param
+pub fn angle_slot(float: ParamIdx) -> ParamIdx {
+    ParamIdx::Dynamic((ANGLE_OFFSET + group_of(float)) as u16)
+}
+
+// The param shown for an input, the paired angle of `ui=angle` floats
+fn shown_slot(annotations: &Annotations, name: &str, slot: ParamIdx) -> ParamIdx {
+    match annotations.input(name).and_then(|a| a.ui) {
+        Some(FloatUi::Angle) => angle_slot(slot),
+        _ => slot,
+    }
+}
+
+// The params of the nth vector input, the sliders of its components
+// or its 3D point. None past `MAX_VECTORS`.
+pub fn vector_slots(n: usize, kind: VectorKind) -> Option<Vec<ParamIdx>> {
+    if n as i32 >= MAX_VECTORS {
+        return None;
+    }
+
+    let base = VECTOR_OFFSET + (n as i32 * VECTOR_PARAMS);
+    let indices = match kind {
+        VectorKind::Point3d => vec![base + VECTOR_PARAMS - 1],
+        kind => (base..base + kind.components() as i32).collect(),
+    };
+
+    Some(
+        indices
+            .into_iter()
+            .map(|i| ParamIdx::Dynamic(i as u16))
+            .collect(),
+    )
+}
+
+// The param backing the named input
+pub fn slot_of(
+    ctx: &RenderContext,
+    annotations: &Annotations,
+    layout: ParamLayout,
+    name: &str,
+) -> Option<ParamIdx> {
+    ctx.iter_inputs()
+        .zip(input_slots(ctx, annotations, layout))
+        .find(|((input, _), _)| *input == name)
+        .and_then(|(_, slot)| slot)
+}
+
+// An error naming the first input that has no param to back it. Host
+// uniforms are filled by the plugin and vector inputs by their own params.
+pub fn check_inputs(
+    ctx: &RenderContext,
+    annotations: &Annotations,
+    layout: ParamLayout,
+) -> Result<(), String> {
+    if annotations.vectors.len() as i32 > MAX_VECTORS {
+        return Err(format!(
+            "Input `{}` is past the {MAX_VECTORS} vector inputs a shader can have.",
+            annotations.vectors[MAX_VECTORS as usize].name
+        ));
+    }
+
+    for vector in &annotations.vectors {
+        let declared = ctx
+            .iter_inputs()
+            .any(|(name, ty)| name == vector.name && matches!(ty, InputType::RawBytes(_)));
+        if !declared {
+            return Err(format!(
+                "Vector input `{}` has no matching uniform field.",
+                vector.name
+            ));
+        }
+    }
+
+    for (name, ty) in ctx.iter_inputs() {
+        let Some(input) = annotations.input(name) else {
+            continue;
+        };
+
+        let is_float = matches!(ty, InputType::Float(_));
+        if !is_float && (input.ui.is_some() || input.precision.is_some()) {
+            return Err(format!(
+                "Input `{name}` sets ui or precision but is not a float."
+            ));
+        }
+
+        if input.space.is_some() && !matches!(ty, InputType::Point(_)) {
+            return Err(format!("Input `{name}` sets space but is not a point."));
+        }
+
+        for other in input.conditions().flat_map(|c| c.inputs()) {
+            let readable = ctx.iter_inputs().any(|(input, ty)| {
+                input == other
+                    && annotations.vector(other).is_none()
+                    && matches!(
+                        Variant::of(ty),
+                        Some(Variant::Float | Variant::Int | Variant::IntList | Variant::Bool)
+                    )
+            });
+            if !readable {
+                return Err(format!(
+                    "Input `{name}` has a condition on `{other}`, which is not a float, \
+                     int or bool input."
+                ));
+            }
+        }
+
+        if let (InputType::Float(f), Some(ui)) = (ty, input.ui) {
+            ui.check_range(f.min, f.default, f.max)
+                .map_err(|e| format!("Input `{name}` {e}"))?;
+        }
+    }
+
+    let slots = input_slots(ctx, annotations, layout);
+
+    for ((name, ty), slot) in ctx.iter_inputs().zip(slots) {
+        if host_uniforms::is_host_uniform(name)
+            || slot.is_some()
+            || annotations.vector(name).is_some()
+        {
+            continue;
+        }
+
+        return Err(unbacked_input_error(name, ty, layout));
+    }
+
+    Ok(())
+}
+
+// Why an input got no param, see `input_slots`
+fn unbacked_input_error(name: &str, ty: &InputType, layout: ParamLayout) -> String {
+    match (Variant::of(ty), layout) {
+        (None, _) => format!(
+            "Input `{name}` has no After Effects parameter. Declare it with an input \
+             pragma, or name it with the `{}` prefix if it is a host uniform.",
+            host_uniforms::PREFIX
+        ),
+        (Some(_), ParamLayout::Legacy) => format!(
+            "Input `{name}` is past the {MAX_INPUTS} inputs of effects saved by an \
+             earlier version, apply the effect again to use more."
+        ),
+        (Some(variant), ParamLayout::Pooled) => format!(
+            "Input `{name}` is past the {MAX_INPUTS} {} inputs a shader can have.",
+            variant.name()
+        ),
+    }
+}
+
+// The param of the first image input, an image filter
+// binds the effect's own layer to it.
+pub fn first_image_slot(
+    ctx: &RenderContext,
+    annotations: &Annotations,
+    layout: ParamLayout,
+) -> Option<ParamIdx> {
+    ctx.iter_inputs()
+        .zip(input_slots(ctx, annotations, layout))
+        .find(|((_, ty), _)| is_image(ty))
+        .and_then(|(_, slot)| slot)
+}
+
+// True for inputs that sample a layer's pixels, audio inputs
+// are backed by a layer param too but read its audio.
+pub fn is_image(ty: &InputType) -> bool {
+    matches!(ty, InputType::Image(_))
 }
 
 pub fn update_param_defaults_and_labels(
@@ -63,20 +394,15 @@ pub fn update_param_defaults_and_labels(
     let Some(local_init) = local.local_init.as_mut() else {
         // Just show the load button if we haven't loaded
         // a shader.
-        for i in ParamIdx::UnloadButton.idx()..PARAM_COUNT {
-            set_param_visibility(state.in_data, ParamIdx::Dynamic(i as u8), false)?;
+        for i in ParamIdx::UnloadButton.idx()..TOTAL_PARAM_COUNT {
+            set_param_visibility(state.in_data, ParamIdx::Dynamic(i as u16), false)?;
         }
         set_param_visibility(state.in_data, ParamIdx::LoadButton, true)?;
 
         return Ok(());
     };
 
-    if !state
-        .params
-        .get(ParamIdx::UseLayerTime)?
-        .as_checkbox()?
-        .value()
-    {
+    if uses_manual_time(state)? {
         set_param_visibility(state.in_data, ParamIdx::Time, true)?;
     }
 
@@ -85,57 +411,102 @@ pub fn update_param_defaults_and_labels(
     }
 
     let param_util_suite = ae::pf::suites::ParamUtils::new()?;
-    for (i, (name, var)) in local_init.ctx.iter_inputs().enumerate() {
-        let index = as_param_index(i, var);
-        set_param_visibility(state.in_data, index, true)?;
+    let layout = local_init.param_layout;
+    let slots = input_slots(&local_init.ctx, &local_init.annotations, layout);
+    for ((name, var), slot) in local_init.ctx.iter_inputs().zip(slots) {
+        let Some(index) = slot else {
+            continue;
+        };
+
+        let input = local_init.annotations.input(name);
+        let ui = input.and_then(|a| a.ui);
+        set_param_visibility(
+            state.in_data,
+            shown_slot(&local_init.annotations, name, index),
+            !is_hidden(&local_init.annotations, name),
+        )?;
+        let label = display_name(&local_init.annotations, name);
+        let supervised = is_condition_input(&local_init.annotations, name);
+        let mut opacity = None;
+        let mut angle = None;
+        let mut float_color = None;
         let mut def = state.params.get_mut(index)?;
-        def.set_name(name);
+        def.set_name(&label);
+        def.set_flag(ParamFlag::SUPERVISE, supervised);
         let param = def.as_param_mut()?;
         match param {
-            ae::Param::CheckBox(mut cb) => {
-                if let InputType::Bool(b) = var {
+            ae::Param::CheckBox(mut cb) => match var {
+                InputType::Bool(b) => {
                     cb.set_default(b.default.is_true());
                     cb.set_value(b.current.is_true());
                 }
-            }
+                InputType::Event(_) => {
+                    cb.set_default(false);
+                    cb.set_value(false);
+                }
+                _ => {}
+            },
             ae::Param::Color(mut co) => {
                 if let InputType::Color(c) = var {
-                    let val = c.default;
-                    co.set_default(PF_Pixel {
-                        alpha: (val[3] * 255.0) as u8,
-                        red: (val[0] * 255.0) as u8,
-                        green: (val[1] * 255.0) as u8,
-                        blue: (val[2] * 255.0) as u8,
-                    });
+                    // AE's color picker is opaque, alpha goes to the paired slider.
+                    // the param's default only holds 8 bits, the value is set
+                    // again as floats once the param is updated.
+                    co.set_default(pixel8(c.default));
+                    co.set_value(pixel8(c.current));
 
-                    let val = c.current;
-                    co.set_value(PF_Pixel {
-                        alpha: (val[3] * 255.0) as u8,
-                        red: (val[0] * 255.0) as u8,
-                        green: (val[1] * 255.0) as u8,
-                        blue: (val[2] * 255.0) as u8,
-                    });
+                    float_color = Some([c.current[0], c.current[1], c.current[2]]);
+                    opacity = Some((c.default[3], c.current[3]));
                 }
             }
             ae::Param::FloatSlider(mut fl) => {
                 if let InputType::Float(f) = var {
-                    fl.set_default(f.default as f64);
-                    fl.set_value(f.current as f64);
-                    fl.set_valid_min(f.min);
-                    fl.set_valid_max(f.max);
-                    fl.set_slider_min(f.min);
-                    fl.set_slider_max(f.max);
+                    let shown = |v: f32| ui.map_or(v as f64, |ui| ui.to_shown(v));
+                    fl.set_default(shown(f.default));
+                    fl.set_value(shown(f.current));
+                    fl.set_valid_min(shown(f.min) as f32);
+                    fl.set_valid_max(shown(f.max) as f32);
+                    fl.set_slider_min(shown(f.min) as f32);
+                    fl.set_slider_max(shown(f.max) as f32);
+                    fl.set_precision(input.and_then(|a| a.precision).unwrap_or(2));
+                    fl.set_display_flags(match ui {
+                        Some(FloatUi::Percent) => ae::ValueDisplayFlag::PERCENT,
+                        Some(FloatUi::Pixels) => ae::ValueDisplayFlag::PIXEL,
+                        _ => ae::ValueDisplayFlag::NONE,
+                    });
+
+                    if ui == Some(FloatUi::Angle) {
+                        angle = Some((f.default, f.current));
+                    }
                 }
             }
             ae::Param::Point(mut p) => {
                 if let InputType::Point(pt) = var {
-                    p.set_default(pt.default.into());
-                    p.set_value(pt.current.into());
+                    // the pragma's values are in the input's space
+                    let space = input.and_then(|a| a.space).unwrap_or_default();
+                    let size = [state.in_data.width(), state.in_data.height()]
+                        .map(|v| (v as f32).max(1.0));
+                    let y_up = local_init.annotations.y_up;
+                    p.set_default(space.to_layer(pt.default, size, y_up).into());
+                    p.set_value(space.to_layer(pt.current, size, y_up).into());
                 }
             }
             ae::Param::Popup(mut il) => {
-                if let InputType::Int(v, Some(_)) = var {
-                    il.set_value(v.current);
+                if let InputType::Int(v, Some(labels)) = var {
+                    // never more options than `options` registered, AE sizes
+                    // the popup when the params are set up
+                    let names = popup_names(labels);
+                    il.set_options(&names.iter().map(String::as_str).collect::<Vec<_>>());
+
+                    // popup values are 1 based positions in the labels
+                    let position = |value: i32| {
+                        labels
+                            .iter()
+                            .take(names.len())
+                            .position(|(_, v)| *v == value)
+                            .map_or(1, |p| p as i32 + 1)
+                    };
+                    il.set_default(position(v.default) as _);
+                    il.set_value(position(v.current));
                 }
             }
             ae::Param::Slider(mut i) => {
@@ -156,6 +527,82 @@ pub fn update_param_defaults_and_labels(
 
         def.set_value_changed();
         param_util_suite.update_param_ui(state.in_data.effect(), index.idx(), &def)?;
+        drop(def);
+
+        // keyframed colors keep their keyframes and the 8 bit value
+        if let Some(rgb) = float_color {
+            set_float_color(state.in_data, index, rgb).ok();
+        }
+
+        if let Some((default, current)) = angle {
+            let angle = angle_slot(index);
+            let mut def = state.params.get_mut(angle)?;
+            def.set_name(&label);
+            def.set_flag(ParamFlag::SUPERVISE, supervised);
+            if let ae::Param::Angle(mut an) = def.as_param_mut()? {
+                an.set_default(default.to_degrees() as f64);
+                an.set_value(current.to_degrees() as f64);
+            }
+            def.set_value_changed();
+            param_util_suite.update_param_ui(state.in_data.effect(), angle.idx(), &def)?;
+        }
+
+        if let Some((default, current)) = opacity {
+            let opacity = opacity_slot(index);
+            set_param_visibility(
+                state.in_data,
+                opacity,
+                !is_hidden(&local_init.annotations, name),
+            )?;
+
+            let mut def = state.params.get_mut(opacity)?;
+            def.set_name(&format!("{label} Opacity"));
+            if let ae::Param::FloatSlider(mut fl) = def.as_param_mut()? {
+                fl.set_default(default as f64 * 100.0);
+                fl.set_value(current as f64 * 100.0);
+            }
+            def.set_value_changed();
+            param_util_suite.update_param_ui(state.in_data.effect(), opacity.idx(), &def)?;
+        }
+    }
+
+    for (n, vector) in local_init.annotations.vectors.iter().enumerate() {
+        let Some(slots) = vector_slots(n, vector.kind) else {
+            continue;
+        };
+
+        let hidden = is_hidden(&local_init.annotations, &vector.name);
+        let label = display_name(&local_init.annotations, &vector.name);
+        for (i, index) in slots.into_iter().enumerate() {
+            set_param_visibility(state.in_data, index, !hidden)?;
+
+            let mut def = state.params.get_mut(index)?;
+            match vector.kind {
+                VectorKind::Point3d => def.set_name(&label),
+                _ => def.set_name(&format!("{label} {}", ["X", "Y", "Z", "W"][i])),
+            }
+            match def.as_param_mut()? {
+                ae::Param::FloatSlider(mut fl) => {
+                    let min = vector.min.map_or(-10_000.0, |m| m[i]);
+                    let max = vector.max.map_or(10_000.0, |m| m[i]);
+                    fl.set_default(vector.default[i] as f64);
+                    fl.set_value(vector.default[i] as f64);
+                    fl.set_valid_min(min);
+                    fl.set_valid_max(max);
+                    fl.set_slider_min(vector.min.map_or(0.0, |m| m[i]));
+                    fl.set_slider_max(vector.max.map_or(1.0, |m| m[i]));
+                }
+                ae::Param::Point3D(mut p) => {
+                    let [x, y, z, _] = vector.default.map(|v| v as f64);
+                    p.set_default((x, y, z));
+                    p.set_value((x, y, z));
+                }
+                _ => {}
+            }
+
+            def.set_value_changed();
+            param_util_suite.update_param_ui(state.in_data.effect(), index.idx(), &def)?;
+        }
     }
 
     local_init.finish_param_visibility_reset();
@@ -170,8 +617,8 @@ pub fn update_param_ui(
         return Ok(());
     };
 
-    for i in ParamIdx::UseLayerTime.idx()..PARAM_COUNT {
-        set_param_visibility(state.in_data, ParamIdx::Dynamic(i as u8), false)?;
+    for i in ParamIdx::UseLayerTime.idx()..TOTAL_PARAM_COUNT {
+        set_param_visibility(state.in_data, ParamIdx::Dynamic(i as u16), false)?;
     }
 
     if local.src.is_none() || local_init.build_error.is_some() {
@@ -184,29 +631,45 @@ pub fn update_param_ui(
         set_param_visibility(state.in_data, ParamIdx::LoadButton, false)?;
         set_param_visibility(state.in_data, ParamIdx::UnloadButton, true)?;
         set_param_visibility(state.in_data, ParamIdx::ReloadButton, true)?;
-        set_param_visibility(state.in_data, ParamIdx::UseLayerTime, true)?;
+        let manual_time = uses_manual_time(state)?;
+        let usage = local_init.utility_usage;
+        let uses_start_date =
+            usage.date && state.params.get(ParamIdx::DateSource)?.as_popup()?.value() == 1;
 
-        if !state
-            .params
-            .get(ParamIdx::UseLayerTime)?
-            .as_checkbox()?
-            .value()
-        {
-            set_param_visibility(state.in_data, ParamIdx::Time, true)?;
-        } else {
-            set_param_visibility(state.in_data, ParamIdx::Time, false)?;
+        for index in EXTENDED_PARAMS {
+            let visible = match index {
+                ParamIdx::DateSource => usage.date,
+                ParamIdx::StartDate => uses_start_date,
+                ParamIdx::Mouse | ParamIdx::MouseDown => usage.mouse,
+                ParamIdx::TimeOffset | ParamIdx::PlaybackSpeed | ParamIdx::LoopDuration => {
+                    !manual_time
+                }
+                _ => true,
+            };
+            set_param_visibility(state.in_data, index, visible)?;
         }
 
-        for (i, (_, var)) in local_init.ctx.iter_inputs().enumerate() {
-            let index = as_param_index(i, var);
-            set_param_visibility(state.in_data, index, true)?;
+        set_param_visibility(state.in_data, ParamIdx::Time, manual_time)?;
+
+        let layout = local_init.param_layout;
+        for (name, params) in shown_params(&local_init.ctx, &local_init.annotations, layout) {
+            // conditional inputs are shown by `update_conditional_params`
+            let conditional = local_init
+                .annotations
+                .input(&name)
+                .is_some_and(|a| a.visible_if.is_some());
+            if is_hidden(&local_init.annotations, &name) || conditional {
+                continue;
+            }
+
+            for index in params {
+                set_param_visibility(state.in_data, index, true)?;
+            }
         }
 
-        let first_image_input = local_init
-            .ctx
-            .iter_inputs()
-            .enumerate()
-            .find(|(_, (_, ty))| ty.is_stored_as_texture());
+        apply_conditions(state, local_init)?;
+
+        let first_image_input = first_image_slot(&local_init.ctx, &local_init.annotations, layout);
 
         // only show image filter options IF we have at least one image input
         set_param_visibility(
@@ -216,22 +679,172 @@ pub fn update_param_ui(
         )?;
 
         // Toggle first image visibility if we are no longer a filter
-        if let Some((i, (_, var))) = first_image_input {
-            let index = as_param_index(i, var);
-
+        if let Some(index) = first_image_input {
             let is_image_filter = state
                 .params
                 .get(ParamIdx::IsImageFilter)?
                 .as_checkbox()?
                 .value();
 
-            set_param_visibility(state.in_data, index, !is_image_filter)?;
+            // left as the pragmas set it otherwise
+            if is_image_filter {
+                set_param_visibility(state.in_data, index, false)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Re-evaluates the `visible_if` and `enabled_if` conditions of every
+// input, called when a param they might read changes.
+pub fn update_conditional_params(
+    state: &mut crate::PluginState,
+    local: &mut crate::Local,
+) -> Result<(), ae::Error> {
+    let Some(local_init) = local.local_init.as_ref() else {
+        return Ok(());
+    };
+
+    if local.src.is_none() || local_init.build_error.is_some() {
+        return Ok(());
+    }
+
+    apply_conditions(state, local_init)
+}
+
+fn apply_conditions(
+    state: &mut crate::PluginState,
+    local_init: &crate::LocalInit,
+) -> Result<(), ae::Error> {
+    let annotations = &local_init.annotations;
+    if !annotations
+        .inputs
+        .values()
+        .any(|a| a.conditions().next().is_some())
+    {
+        return Ok(());
+    }
+
+    let layout = local_init.param_layout;
+    let param_util_suite = ae::pf::suites::ParamUtils::new()?;
+    let first_image = first_image_slot(&local_init.ctx, annotations, layout);
+    let is_image_filter = state
+        .params
+        .get(ParamIdx::IsImageFilter)?
+        .as_checkbox()?
+        .value();
+
+    for (name, params) in shown_params(&local_init.ctx, annotations, layout) {
+        let Some(input) = annotations.input(&name) else {
+            continue;
+        };
+
+        let value = |other: &str| input_value(state, local_init, other);
+
+        if let Some(condition) = &input.visible_if {
+            let visible = !input.hidden && condition.holds(value);
+            for index in &params {
+                // an image filter's main input stays hidden
+                let main_input = is_image_filter && first_image == Some(*index);
+                set_param_visibility(state.in_data, *index, visible && !main_input)?;
+            }
+        }
+
+        if let Some(condition) = &input.enabled_if {
+            let enabled = condition.holds(value);
+            for index in params {
+                let mut def = state.params.get_mut(index)?;
+                def.set_ui_flag(ae::ParamUIFlags::DISABLED, !enabled);
+                param_util_suite.update_param_ui(state.in_data.effect(), index.idx(), &def)?;
+            }
         }
     }
 
     Ok(())
 }
 
+// Every param shown for each input, including opacity
+// sliders and the params of vector inputs.
+fn shown_params(
+    ctx: &RenderContext,
+    annotations: &Annotations,
+    layout: ParamLayout,
+) -> Vec<(String, Vec<ParamIdx>)> {
+    let mut out: Vec<_> = ctx
+        .iter_inputs()
+        .zip(input_slots(ctx, annotations, layout))
+        .filter_map(|((name, ty), slot)| {
+            let index = slot?;
+            let mut params = vec![shown_slot(annotations, name, index)];
+            if Variant::of(ty) == Some(Variant::Color) {
+                params.push(opacity_slot(index));
+            }
+            Some((name.to_owned(), params))
+        })
+        .collect();
+
+    for (n, vector) in annotations.vectors.iter().enumerate() {
+        if let Some(params) = vector_slots(n, vector.kind) {
+            out.push((vector.name.clone(), params));
+        }
+    }
+
+    out
+}
+
+// The value the shader receives for a scalar input, read from its param.
+// None for inputs a condition can not compare.
+fn input_value(
+    state: &crate::PluginState,
+    local_init: &crate::LocalInit,
+    name: &str,
+) -> Option<f32> {
+    let annotations = &local_init.annotations;
+    let layout = local_init.param_layout;
+    let slot = slot_of(&local_init.ctx, annotations, layout, name)?;
+    let ui = annotations.input(name).and_then(|a| a.ui);
+    let def = state.params.get(shown_slot(annotations, name, slot)).ok()?;
+
+    if let Ok(cb) = def.as_checkbox() {
+        return Some(cb.value() as i32 as f32);
+    }
+    if let Ok(slider) = def.as_slider() {
+        return Some(slider.value() as f32);
+    }
+    if let Ok(angle) = def.as_angle() {
+        return Some((angle.value() as f32).to_radians());
+    }
+    if let Ok(fl) = def.as_float_slider() {
+        return Some(ui.map_or(fl.value() as f32, |ui| ui.from_shown(fl.value())));
+    }
+
+    // popups pick the nth label, the shader receives its value
+    let position = def.as_popup().ok()?.value();
+    local_init
+        .ctx
+        .iter_inputs()
+        .find_map(|(input, ty)| match ty {
+            InputType::Int(_, Some(labels)) if input == name => {
+                popup_value(labels, position).map(|v| v as f32)
+            }
+            _ => None,
+        })
+}
+
+// True if the Time param drives the shader, either picked in the Time
+// Source popup or set through the older Use Layer Time checkbox.
+fn uses_manual_time(state: &crate::PluginState) -> Result<bool, Error> {
+    let use_layer_time = state
+        .params
+        .get(ParamIdx::UseLayerTime)?
+        .as_checkbox()?
+        .value();
+    let source = state.params.get(ParamIdx::TimeSource)?.as_popup()?.value();
+
+    Ok(!use_layer_time || source == 3)
+}
+
 fn default_flags() -> ParamFlag {
     ParamFlag::CANNOT_TIME_VARY
         | ParamFlag::TWIRLY
@@ -298,68 +911,174 @@ pub fn setup_static_params(params: &mut ae::Parameters<ParamIdx>) -> Result<(),
     Ok(())
 }
 
+// params that live after the variant backing, see `EXTENDED_PARAMS`
+pub fn setup_extended_params(params: &mut ae::Parameters<ParamIdx>) -> Result<(), Error> {
+    params.add(
+        ParamIdx::ExpandPixels,
+        "Expand Pixels",
+        ae::FloatSliderDef::setup(|f| {
+            f.set_default(0.);
+            f.set_valid_min(0.);
+            f.set_valid_max(4_000.);
+            f.set_slider_min(0.);
+            f.set_slider_max(200.);
+            f.set_precision(0);
+        }),
+    )?;
+
+    params.add(
+        ParamIdx::MotionBlurSamples,
+        "Motion Blur Samples",
+        ae::SliderDef::setup(|f| {
+            f.set_default(8);
+            f.set_valid_min(1);
+            f.set_valid_max(64);
+            f.set_slider_min(1);
+            f.set_slider_max(32);
+        }),
+    )?;
+
+    params.add(
+        ParamIdx::ShutterAngle,
+        "Shutter Angle",
+        ae::FloatSliderDef::setup(|f| {
+            f.set_default(180.);
+            f.set_valid_min(0.);
+            f.set_valid_max(720.);
+            f.set_slider_min(0.);
+            f.set_slider_max(360.);
+            f.set_precision(0);
+        }),
+    )?;
+
+    params.add_with_flags(
+        ParamIdx::UseCompShutter,
+        "Use Comp Shutter",
+        ae::CheckBoxDef::setup(|f| {
+            f.set_label("Enabled");
+            f.set_default(true);
+        }),
+        default_flags(),
+        ae::ParamUIFlags::empty(),
+    )?;
+
+    // option n renders at n times the output resolution
+    params.add(
+        ParamIdx::Supersampling,
+        "Supersampling",
+        ae::PopupDef::setup(|f| {
+            f.set_options(&["Off", "2x", "3x", "4x"]);
+            f.set_default(1);
+        }),
+    )?;
+
+    params.add(
+        ParamIdx::SupersampleFilter,
+        "Supersample Filter",
+        ae::PopupDef::setup(|f| {
+            f.set_options(&["Box", "Lanczos"]);
+            f.set_default(1);
+        }),
+    )?;
+
+    params.add_with_flags(
+        ParamIdx::DateSource,
+        "Date",
+        ae::PopupDef::setup(|f| {
+            f.set_options(&["Start Date + Comp Time", "Wall Clock"]);
+            f.set_default(1);
+        }),
+        default_flags(),
+        ae::ParamUIFlags::empty(),
+    )?;
+
+    // YYYYMMDD, a float slider is the only param wide enough
+    params.add(
+        ParamIdx::StartDate,
+        "Start Date",
+        ae::FloatSliderDef::setup(|f| {
+            f.set_default(20_000_101.);
+            f.set_valid_min(10_000_101.);
+            f.set_valid_max(99_991_231.);
+            f.set_slider_min(19_700_101.);
+            f.set_slider_max(20_991_231.);
+            f.set_precision(0);
+        }),
+    )?;
+
+    params.add(ParamIdx::Mouse, "Mouse", ae::PointDef::setup(point))?;
+
+    params.add(
+        ParamIdx::MouseDown,
+        "Mouse Down",
+        ae::CheckBoxDef::setup(|f| {
+            f.set_label("Down");
+            f.set_default(false);
+        }),
+    )?;
+
+    params.add_with_flags(
+        ParamIdx::TimeSource,
+        "Time Source",
+        ae::PopupDef::setup(|f| {
+            f.set_options(&["Layer Time", "Comp Time", "Manual"]);
+            f.set_default(1);
+        }),
+        default_flags(),
+        ae::ParamUIFlags::empty(),
+    )?;
+
+    params.add(
+        ParamIdx::TimeOffset,
+        "Time Offset",
+        ae::FloatSliderDef::setup(|f| {
+            f.set_default(0.);
+            f.set_valid_min(-100_000.);
+            f.set_valid_max(100_000.);
+            f.set_slider_min(-10.);
+            f.set_slider_max(10.);
+            f.set_precision(2);
+        }),
+    )?;
+
+    params.add(
+        ParamIdx::PlaybackSpeed,
+        "Playback Speed",
+        ae::FloatSliderDef::setup(|f| {
+            f.set_default(1.);
+            f.set_valid_min(-100.);
+            f.set_valid_max(100.);
+            f.set_slider_min(0.);
+            f.set_slider_max(4.);
+            f.set_precision(2);
+        }),
+    )?;
+
+    // seconds, 0 does not loop
+    params.add(
+        ParamIdx::LoopDuration,
+        "Loop Duration",
+        ae::FloatSliderDef::setup(|f| {
+            f.set_default(0.);
+            f.set_valid_min(0.);
+            f.set_valid_max(100_000.);
+            f.set_slider_min(0.);
+            f.set_slider_max(30.);
+            f.set_precision(2);
+        }),
+    )?;
+
+    Ok(())
+}
+
 // create one param of every type to back
 // a single input variant in the render context
 pub fn create_variant_backing(params: &mut ae::Parameters<ParamIdx>) -> Result<(), Error> {
     let mut base_index = STATIC_PARAMS_OFFSET;
     for _ in 0..MAX_INPUTS {
-        for offset in 0..PARAM_TYPE_COUNT {
-            let name = format!("INPUT {}", base_index + offset);
-            let index = ParamIdx::Dynamic(base_index as u8 + offset as u8);
-            let ui_flags = ae::ParamUIFlags::empty();
-            let param_flag = ParamFlag::TWIRLY | ParamFlag::SKIP_REVEAL_WHEN_UNHIDDEN;
-            match offset as usize {
-                f if f == Variant::Float as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::FloatSliderDef::setup(float),
-                    param_flag,
-                    ui_flags,
-                )?,
-                i if i == Variant::Int as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::SliderDef::setup(int),
-                    param_flag,
-                    ui_flags,
-                )?,
-                i if i == Variant::IntList as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::PopupDef::setup(options),
-                    param_flag,
-                    ui_flags,
-                )?,
-                pt if pt == Variant::Point as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::PointDef::setup(point),
-                    param_flag,
-                    ui_flags,
-                )?,
-                b if b == Variant::Bool as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::CheckBoxDef::setup(bool),
-                    param_flag,
-                    ui_flags,
-                )?,
-                c if c == Variant::Color as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::ColorDef::setup(color),
-                    param_flag,
-                    ui_flags,
-                )?,
-                i if i == Variant::Image as usize => params.add_with_flags(
-                    index,
-                    &name,
-                    ae::LayerDef::setup(layer),
-                    param_flag,
-                    ui_flags,
-                )?,
-                _ => {}
-            }
+        for (offset, variant) in VARIANTS.iter().enumerate() {
+            let index = base_index + offset as i32;
+            add_backing_param(params, index, *variant)?;
         }
         base_index += PARAM_TYPE_COUNT;
     }
@@ -367,6 +1086,165 @@ pub fn create_variant_backing(params: &mut ae::Parameters<ParamIdx>) -> Result<(
     Ok(())
 }
 
+// create the opacity sliders paired with color inputs, see `OPACITY_OFFSET`
+pub fn create_opacity_backing(params: &mut ae::Parameters<ParamIdx>) -> Result<(), Error> {
+    for index in OPACITY_OFFSET..OPACITY_OFFSET + OPACITY_COUNT {
+        params.add_with_flags(
+            ParamIdx::Dynamic(index as u16),
+            &format!("INPUT {index}"),
+            ae::FloatSliderDef::setup(opacity),
+            ParamFlag::TWIRLY | ParamFlag::SKIP_REVEAL_WHEN_UNHIDDEN,
+            ae::ParamUIFlags::empty(),
+        )?;
+    }
+
+    Ok(())
+}
+
+// create the angle params paired with floats, see `ANGLE_OFFSET`
+pub fn create_angle_backing(params: &mut ae::Parameters<ParamIdx>) -> Result<(), Error> {
+    for index in ANGLE_OFFSET..TOTAL_PARAM_COUNT {
+        params.add_with_flags(
+            ParamIdx::Dynamic(index as u16),
+            &format!("INPUT {index}"),
+            ae::AngleDef::setup(angle),
+            ParamFlag::TWIRLY | ParamFlag::SKIP_REVEAL_WHEN_UNHIDDEN,
+            ae::ParamUIFlags::empty(),
+        )?;
+    }
+
+    Ok(())
+}
+
+// create the params backing vector inputs, see `VECTOR_OFFSET`
+pub fn create_vector_backing(params: &mut ae::Parameters<ParamIdx>) -> Result<(), Error> {
+    for index in VECTOR_OFFSET..ANGLE_OFFSET {
+        let name = format!("INPUT {index}");
+        let param_flag = ParamFlag::TWIRLY | ParamFlag::SKIP_REVEAL_WHEN_UNHIDDEN;
+        let ui_flags = ae::ParamUIFlags::empty();
+        let index = ParamIdx::Dynamic(index as u16);
+
+        if (index.idx() - VECTOR_OFFSET) % VECTOR_PARAMS == VECTOR_PARAMS - 1 {
+            params.add_with_flags(
+                index,
+                &name,
+                ae::Point3DDef::setup(point3d),
+                param_flag,
+                ui_flags,
+            )?;
+        } else {
+            params.add_with_flags(
+                index,
+                &name,
+                ae::FloatSliderDef::setup(float),
+                param_flag,
+                ui_flags,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn add_backing_param(
+    params: &mut ae::Parameters<ParamIdx>,
+    index: i32,
+    variant: Variant,
+) -> Result<(), Error> {
+    let name = format!("INPUT {index}");
+    let index = ParamIdx::Dynamic(index as u16);
+    let ui_flags = ae::ParamUIFlags::empty();
+    // supervised once a shader's conditions read it, see `is_condition_input`
+    let param_flag = ParamFlag::TWIRLY | ParamFlag::SKIP_REVEAL_WHEN_UNHIDDEN;
+    match variant {
+        Variant::Float => params.add_with_flags(
+            index,
+            &name,
+            ae::FloatSliderDef::setup(float),
+            param_flag,
+            ui_flags,
+        ),
+        Variant::Int => params.add_with_flags(
+            index,
+            &name,
+            ae::SliderDef::setup(int),
+            param_flag,
+            ui_flags,
+        ),
+        Variant::IntList => params.add_with_flags(
+            index,
+            &name,
+            ae::PopupDef::setup(options),
+            param_flag,
+            ui_flags,
+        ),
+        Variant::Point => params.add_with_flags(
+            index,
+            &name,
+            ae::PointDef::setup(point),
+            param_flag,
+            ui_flags,
+        ),
+        Variant::Bool => params.add_with_flags(
+            index,
+            &name,
+            ae::CheckBoxDef::setup(bool),
+            param_flag,
+            ui_flags,
+        ),
+        Variant::Color => params.add_with_flags(
+            index,
+            &name,
+            ae::ColorDef::setup(color),
+            param_flag,
+            ui_flags,
+        ),
+        Variant::Image => params.add_with_flags(
+            index,
+            &name,
+            ae::LayerDef::setup(layer),
+            param_flag,
+            ui_flags,
+        ),
+    }
+}
+
+// An input's color rounded to the 8 bits a color param's default holds
+fn pixel8(color: [f32; 4]) -> ae::Pixel8 {
+    let [red, green, blue] =
+        [color[0], color[1], color[2]].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    ae::Pixel8 {
+        alpha: 255,
+        red,
+        green,
+        blue,
+    }
+}
+
+// Sets a color param's value through its stream, which holds floats,
+// so colors above 1.0 are not clipped to the param's 8 bits.
+fn set_float_color(
+    in_data: InData,
+    index: ParamIdx,
+    [red, green, blue]: [f32; 3],
+) -> Result<(), Error> {
+    let plugin_id = *crate::PLUGIN_ID.get().unwrap_or(&10);
+    let stream_suite = suites::Stream::new()?;
+    let effect = suites::PFInterface::new()?.new_effect_for_effect(in_data.effect(), plugin_id)?;
+    let stream = stream_suite.new_effect_stream_by_index(effect, plugin_id, index.idx())?;
+
+    stream_suite.set_stream_value(
+        plugin_id,
+        stream,
+        &StreamValue::Color {
+            alpha: 1.0,
+            red: red as f64,
+            green: green as f64,
+            blue: blue as f64,
+        },
+    )
+}
+
 pub fn set_param_visibility(in_data: InData, index: ParamIdx, visible: bool) -> Result<(), Error> {
     let dyn_stream_suite = suites::DynamicStream::new()?;
     let stream_suite = suites::Stream::new()?;
@@ -403,19 +1281,81 @@ fn color(f: &mut ae::ColorDef) {
     });
 }
 
+// percent
+fn opacity(f: &mut ae::FloatSliderDef) {
+    f.set_default(100.);
+    f.set_valid_min(0.);
+    f.set_valid_max(100.);
+    f.set_slider_min(0.);
+    f.set_slider_max(100.);
+    f.set_precision(1);
+}
+
 fn point(f: &mut ae::PointDef) {
     f.set_default((0.0, 0.0));
 }
 
+// degrees
+fn angle(f: &mut ae::AngleDef) {
+    f.set_default(0.0);
+}
+
+fn point3d(f: &mut ae::Point3DDef) {
+    f.set_default((0.0, 0.0, 0.0));
+}
+
 fn bool(f: &mut ae::CheckBoxDef) {
     f.set_label("Enabled");
     f.set_default(false);
 }
 
+// AE copies popup names on update_param_ui, they are limited in
+// count and length and treat `|` and a leading `(` as markup.
+const MAX_POPUP_OPTIONS: usize = 64;
+const MAX_POPUP_NAME_LEN: usize = 31;
+
+// The option names of a labeled int, cleaned up for AE. Labels past
+// `MAX_POPUP_OPTIONS` are dropped, their values can not be picked.
+pub fn popup_names(labels: &[(String, i32)]) -> Vec<String> {
+    labels
+        .iter()
+        .take(MAX_POPUP_OPTIONS)
+        .map(|(label, value)| {
+            // AE greys out an option starting with `(`, later ones are kept
+            let label = label.trim();
+            let name: String = label
+                .strip_prefix('(')
+                .unwrap_or(label)
+                .replace('|', "/")
+                .chars()
+                .take(MAX_POPUP_NAME_LEN)
+                .collect();
+
+            if name.is_empty() {
+                value.to_string()
+            } else {
+                name
+            }
+        })
+        .collect()
+}
+
+// The value of the label at a popup's 1 based `position`, None
+// for positions outside the labels, 0 included.
+pub fn popup_value(labels: &[(String, i32)], position: i32) -> Option<i32> {
+    let index = usize::try_from(position).ok()?.checked_sub(1)?;
+    labels.get(index).map(|(_, v)| *v)
+}
+
 fn options(f: &mut ae::PopupDef) {
-    // it is unsafe to dynamically set options
-    f.set_options(&["option 1", "option 2", "option 3", "option 4", "option 5"]);
-    f.set_default(0);
+    // placeholders, replaced by `popup_names` when a shader loads. One
+    // per option a shader may show, so the popup only ever shrinks.
+    let names: Vec<_> = (1..=MAX_POPUP_OPTIONS)
+        .map(|n| format!("option {n}"))
+        .collect();
+    f.set_options(&names.iter().map(String::as_str).collect::<Vec<_>>());
+    // popup values are 1 based
+    f.set_default(1);
 }
 
 fn int(f: &mut ae::SliderDef) {
@@ -434,3 +1374,199 @@ fn float(f: &mut ae::FloatSliderDef) {
     f.set_slider_max(1.0);
     f.set_precision(2);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tweak_shader::input_type::{
+        BoundedInput, DiscreteInput, RawBytes, ShaderBool, TextureStatus,
+    };
+
+    fn bounded<T: Copy>(value: T) -> BoundedInput<T> {
+        BoundedInput {
+            current: value,
+            min: value,
+            max: value,
+            default: value,
+        }
+    }
+
+    fn discrete<T: Copy>(value: T) -> DiscreteInput<T> {
+        DiscreteInput {
+            current: value,
+            default: value,
+        }
+    }
+
+    fn raw_bytes() -> InputType {
+        InputType::RawBytes(RawBytes { inner: vec![0; 16] })
+    }
+
+    // one input of every type tweak_shader has
+    fn every_input_type() -> Vec<(InputType, Option<Variant>)> {
+        vec![
+            (InputType::Float(bounded(0.5)), Some(Variant::Float)),
+            (InputType::Int(bounded(1), None), Some(Variant::Int)),
+            (
+                InputType::Int(bounded(1), Some(vec![("One".to_owned(), 1)])),
+                Some(Variant::IntList),
+            ),
+            (InputType::Point(bounded([0.0, 0.0])), Some(Variant::Point)),
+            (
+                InputType::Bool(discrete(ShaderBool::False)),
+                Some(Variant::Bool),
+            ),
+            (InputType::Event(0), Some(Variant::Bool)),
+            (
+                InputType::Color(discrete([1.0, 1.0, 1.0, 1.0])),
+                Some(Variant::Color),
+            ),
+            (
+                InputType::Image(TextureStatus::Uninit),
+                Some(Variant::Image),
+            ),
+            (
+                InputType::Audio(TextureStatus::Uninit, None),
+                Some(Variant::Image),
+            ),
+            (
+                InputType::AudioFft(TextureStatus::Uninit, Some(128)),
+                Some(Variant::Image),
+            ),
+            (raw_bytes(), None),
+        ]
+    }
+
+    #[test]
+    fn every_input_type_maps_to_its_variant() {
+        for (ty, variant) in every_input_type() {
+            assert_eq!(Variant::of(&ty), variant, "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn variants_are_in_group_order() {
+        for (i, variant) in VARIANTS.iter().enumerate() {
+            assert_eq!(*variant as usize, i);
+        }
+    }
+
+    #[test]
+    fn paired_params_follow_their_group() {
+        let param = |group: i32, variant: Variant| {
+            ParamIdx::Dynamic(
+                (STATIC_PARAMS_OFFSET + group * PARAM_TYPE_COUNT + variant as i32) as u16,
+            )
+        };
+
+        assert_eq!(opacity_slot(param(0, Variant::Color)).idx(), OPACITY_OFFSET);
+        assert_eq!(
+            opacity_slot(param(MAX_INPUTS - 1, Variant::Color)).idx(),
+            VECTOR_OFFSET - 1
+        );
+        assert_eq!(angle_slot(param(3, Variant::Float)).idx(), ANGLE_OFFSET + 3);
+        assert_eq!(
+            angle_slot(param(MAX_INPUTS - 1, Variant::Float)).idx(),
+            TOTAL_PARAM_COUNT - 1
+        );
+    }
+
+    #[test]
+    fn only_inputs_read_by_conditions_are_supervised() {
+        let src = "#pragma input(int, name=\"mode\", default=0)\n\
+                   #pragma input(float, name=\"scale\", enabled_if=\"useScale\")\n\
+                   #pragma input(float, name=\"noise\", visible_if=\"mode==2 || scale>1\")\n\
+                   #pragma input(bool, name=\"useScale\", hidden)";
+        let (_, annotations) = crate::annotations::extract(src).unwrap();
+
+        assert!(is_condition_input(&annotations, "mode"));
+        assert!(is_condition_input(&annotations, "scale"));
+        assert!(is_condition_input(&annotations, "useScale"));
+        assert!(!is_condition_input(&annotations, "noise"));
+    }
+
+    #[test]
+    fn paired_params_follow_the_extended_params() {
+        assert_eq!(
+            OPACITY_OFFSET,
+            ParamIdx::LoopDuration.idx() + 1,
+            "the first dynamic param after the extended params moved"
+        );
+    }
+
+    #[test]
+    fn unsupported_inputs_ask_for_a_pragma_or_the_host_prefix() {
+        for layout in [ParamLayout::Legacy, ParamLayout::Pooled] {
+            let error = unbacked_input_error("data", &raw_bytes(), layout);
+            assert!(error.starts_with("Input `data` has no After Effects parameter"));
+            assert!(error.contains("`ae_` prefix"));
+        }
+    }
+
+    #[test]
+    fn supported_inputs_past_the_limits_name_the_limit() {
+        for (ty, variant) in every_input_type() {
+            let Some(variant) = variant else {
+                continue;
+            };
+
+            let pooled = unbacked_input_error("x", &ty, ParamLayout::Pooled);
+            assert_eq!(
+                pooled,
+                format!(
+                    "Input `x` is past the 32 {} inputs a shader can have.",
+                    variant.name()
+                )
+            );
+
+            let legacy = unbacked_input_error("x", &ty, ParamLayout::Legacy);
+            assert!(legacy.starts_with("Input `x` is past the 32 inputs"));
+        }
+    }
+
+    #[test]
+    fn color_defaults_round_and_clamp_to_8_bits() {
+        let pixel = pixel8([0.5, 1.5, -0.2, 0.25]);
+        assert_eq!(
+            [pixel.alpha, pixel.red, pixel.green, pixel.blue],
+            [255, 128, 255, 0]
+        );
+
+        let pixel = pixel8([0.999, 0.002, 1.0, 1.0]);
+        assert_eq!([pixel.red, pixel.green, pixel.blue], [255, 1, 255]);
+    }
+
+    #[test]
+    fn popup_names_strip_a_single_leading_paren() {
+        let labels = [
+            ("(Disabled", 1),
+            ("((Nested)", 2),
+            ("Left | Right", 3),
+            (" ( ", 4),
+            ("Size (px)", 5),
+        ]
+        .map(|(label, value)| (label.to_owned(), value));
+
+        assert_eq!(
+            popup_names(&labels),
+            ["Disabled", "(Nested)", "Left / Right", "4", "Size (px)"]
+        );
+    }
+
+    #[test]
+    fn popups_keep_to_the_registered_options() {
+        let labels: Vec<_> = (0..100).map(|v| (format!("Option {v}"), v)).collect();
+        assert_eq!(popup_names(&labels).len(), MAX_POPUP_OPTIONS);
+    }
+
+    #[test]
+    fn popup_positions_outside_the_labels_have_no_value() {
+        let labels = [("Off", 0), ("On", 4)].map(|(label, value)| (label.to_owned(), value));
+
+        assert_eq!(popup_value(&labels, 1), Some(0));
+        assert_eq!(popup_value(&labels, 2), Some(4));
+        assert_eq!(popup_value(&labels, 0), None);
+        assert_eq!(popup_value(&labels, -1), None);
+        assert_eq!(popup_value(&labels, 3), None);
+    }
+}
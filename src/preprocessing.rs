@@ -1,11 +1,12 @@
+use std::collections::HashSet;
+
 use glsl::parser::Parse;
 use glsl::syntax::{
-    Declaration, Expr, ExternalDeclaration, FullySpecifiedType, FunctionPrototype,
-    InitDeclaratorList, SimpleStatement, SingleDeclaration, TypeQualifier, TypeQualifierSpec,
-    TypeSpecifier, TypeSpecifierNonArray,
+    Block, Declaration, Expr, ExternalDeclaration, FullySpecifiedType, FunctionPrototype,
+    InitDeclaratorList, Preprocessor, SimpleStatement, SingleDeclaration, TypeQualifier,
+    TypeQualifierSpec, TypeSpecifier, TypeSpecifierNonArray,
 };
-use glsl::visitor::HostMut;
-use glsl::visitor::VisitorMut;
+use glsl::visitor::{Host, HostMut, Visit, Visitor, VisitorMut};
 
 const TEXTURE_SAMPLING_FUNCTIONS: [&str; 10] = [
     "texture",
@@ -171,3 +172,375 @@ pub fn convert_output_to_ae_format(module: &str) -> Result<String, String> {
 
     Ok(output)
 }
+
+// utility block fields are found by position, these change every frame.
+// time, frame_index and date.
+const TIME_DEPENDENT_UTILITY_FIELDS: [usize; 3] = [0, 3, 5];
+const MOUSE_UTILITY_FIELD: usize = 4;
+const DATE_UTILITY_FIELD: usize = 5;
+
+struct IdentifierCollector {
+    pub names: HashSet<String>,
+}
+
+impl Visitor for IdentifierCollector {
+    fn visit_expr(&mut self, e: &Expr) -> Visit {
+        match e {
+            Expr::Variable(ident) => {
+                self.names.insert(ident.0.clone());
+            }
+            Expr::Dot(_, field) => {
+                self.names.insert(field.0.clone());
+            }
+            _ => {}
+        }
+
+        Visit::Children
+    }
+}
+
+// True if the shader reads the time, frame index or date fields of its
+// utility block. Sources that fail to parse are assumed to.
+pub fn reads_time(module: &str) -> bool {
+    match read_utility_fields(module) {
+        Some(read) => TIME_DEPENDENT_UTILITY_FIELDS
+            .iter()
+            .any(|f| read.contains(f)),
+        None => true,
+    }
+}
+
+// Which of the utility block fields the host fills from params are read,
+// their params are hidden otherwise.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UtilityUsage {
+    pub mouse: bool,
+    pub date: bool,
+}
+
+pub fn utility_usage(module: &str) -> UtilityUsage {
+    match read_utility_fields(module) {
+        Some(read) => UtilityUsage {
+            mouse: read.contains(&MOUSE_UTILITY_FIELD),
+            date: read.contains(&DATE_UTILITY_FIELD),
+        },
+        None => UtilityUsage {
+            mouse: true,
+            date: true,
+        },
+    }
+}
+
+// Positions of the utility block fields the shader reads,
+// None if the source fails to parse.
+fn read_utility_fields(module: &str) -> Option<HashSet<usize>> {
+    let unit = glsl::syntax::TranslationUnit::parse(module).ok()?;
+
+    let utility_block = unit.0.iter().find_map(|item| match item {
+        ExternalDeclaration::Preprocessor(Preprocessor::Pragma(pragma)) => pragma
+            .command
+            .trim()
+            .strip_prefix("utility_block")
+            .map(|rest| {
+                rest.trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace())
+                    .to_owned()
+            }),
+        _ => None,
+    });
+
+    let Some(utility_block) = utility_block else {
+        return Some(HashSet::new());
+    };
+
+    let fields: Vec<String> = unit
+        .0
+        .iter()
+        .find_map(|item| match item {
+            ExternalDeclaration::Declaration(Declaration::Block(Block {
+                name, fields, ..
+            })) if name.0 == utility_block => Some(
+                fields
+                    .iter()
+                    .flat_map(|field| field.identifiers.0.iter())
+                    .map(|ident| ident.ident.0.clone())
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let mut collector = IdentifierCollector {
+        names: HashSet::new(),
+    };
+    unit.visit(&mut collector);
+
+    Some(
+        fields
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| collector.names.contains(*f))
+            .map(|(i, _)| i)
+            .collect(),
+    )
+}
+
+// Pixel offset of the tile being rendered, filled by the plugin when
+// a supersampled frame is split into tiles.
+pub const TILE_OFFSET: &str = "ae_tile_offset";
+
+const FRAG_COORD: &str = "gl_FragCoord";
+const FRAG_COORD_FN: &str = "ae_frag_coord";
+
+struct FragCoordReplacer;
+
+impl VisitorMut for FragCoordReplacer {
+    fn visit_expr(&mut self, e: &mut Expr) -> Visit {
+        if matches!(e, Expr::Variable(ident) if ident.0 == FRAG_COORD) {
+            *e = Expr::FunCall(
+                glsl::syntax::FunIdentifier::Identifier(FRAG_COORD_FN.into()),
+                Vec::new(),
+            );
+            return Visit::Parent;
+        }
+
+        Visit::Children
+    }
+}
+
+fn is_push_constant_block(block: &Block) -> bool {
+    block.qualifier.qualifiers.0.iter().any(|spec| match spec {
+        TypeQualifierSpec::Layout(layout) => layout.ids.0.iter().any(|id| {
+            matches!(
+                id,
+                glsl::syntax::LayoutQualifierSpec::Identifier(ident, None) if ident.0 == "push_constant"
+            )
+        }),
+        _ => false,
+    })
+}
+
+// Rewrites reads of `gl_FragCoord` to add `ae_tile_offset`, so a frame can be
+// rendered in tiles that each believe they are part of the whole target.
+// The offset is appended to the shader's push constant block, or a new one.
+// Returns None if the shader never reads `gl_FragCoord`.
+pub fn offset_frag_coord(module: &str) -> Result<Option<String>, String> {
+    let mut unit = glsl::syntax::TranslationUnit::parse(module)
+        .map_err(|e| format!("failed to mangle: {e}"))?;
+
+    let mut collector = IdentifierCollector {
+        names: HashSet::new(),
+    };
+    unit.visit(&mut collector);
+
+    if !collector.names.contains(FRAG_COORD) {
+        return Ok(None);
+    }
+
+    unit.visit_mut(&mut FragCoordReplacer);
+
+    let offset_block = format!("layout(push_constant) uniform AeTile {{ vec2 {TILE_OFFSET}; }};");
+    let helper = format!(
+        "vec4 {FRAG_COORD_FN}() {{ return {FRAG_COORD} + vec4({TILE_OFFSET}, 0.0, 0.0); }}"
+    );
+
+    let ExternalDeclaration::Declaration(Declaration::Block(offset_block)) =
+        ExternalDeclaration::parse(offset_block).map_err(|e| format!("failed to mangle: {e}"))?
+    else {
+        return Err("failed to mangle: tile offset block".to_owned());
+    };
+    let helper =
+        ExternalDeclaration::parse(helper).map_err(|e| format!("failed to mangle: {e}"))?;
+
+    let items = &mut (unit.0).0;
+
+    let first_function = items
+        .iter()
+        .position(|item| matches!(item, ExternalDeclaration::FunctionDefinition(_)))
+        .unwrap_or(items.len());
+
+    let existing = items
+        .iter_mut()
+        .enumerate()
+        .find_map(|(i, item)| match item {
+            ExternalDeclaration::Declaration(Declaration::Block(block))
+                if is_push_constant_block(block) =>
+            {
+                Some((i, block))
+            }
+            _ => None,
+        });
+
+    // only one push constant block is allowed per stage
+    let helper_index = match existing {
+        Some((i, block)) => {
+            block.fields.extend(offset_block.fields);
+            first_function.max(i + 1)
+        }
+        None => {
+            items.insert(
+                first_function,
+                ExternalDeclaration::Declaration(Declaration::Block(offset_block)),
+            );
+            first_function + 1
+        }
+    };
+
+    items.insert(helper_index, helper);
+
+    let mut output = String::new();
+    glsl::transpiler::glsl::show_translation_unit(&mut output, &unit);
+
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fields are found by position, so these names differ from the ReadMe's
+    const UTILITY_BLOCK: &str = "
+#pragma utility_block(Inputs)
+layout(push_constant) uniform Inputs {
+    float t;
+    float dt;
+    float fps;
+    uint frame;
+    vec4 cursor;
+    vec4 day;
+    vec3 res;
+    uint pass_index;
+};
+
+layout(location = 0) out vec4 out_color;
+";
+
+    fn shader(body: &str) -> String {
+        format!("{UTILITY_BLOCK}\nvoid main() {{\n    out_color = {body};\n}}\n")
+    }
+
+    #[test]
+    fn reads_time_through_the_utility_block() {
+        assert!(reads_time(&shader("vec4(sin(t))")));
+        assert!(reads_time(&shader("vec4(float(frame))")));
+        assert!(reads_time(&shader("day")));
+    }
+
+    #[test]
+    fn other_utility_fields_are_not_time() {
+        let src = shader("vec4(res.xy / 100.0, dt * fps, cursor.x)");
+        assert!(!reads_time(&src));
+
+        let usage = utility_usage(&src);
+        assert!(usage.mouse);
+        assert!(!usage.date);
+    }
+
+    #[test]
+    fn reports_date_and_mouse_reads() {
+        let usage = utility_usage(&shader("vec4(day.w) + cursor"));
+        assert!(usage.mouse && usage.date);
+
+        let usage = utility_usage(&shader("vec4(t)"));
+        assert!(!usage.mouse && !usage.date);
+    }
+
+    #[test]
+    fn shaders_without_a_utility_block_read_nothing() {
+        let src = "
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    float time = 1.0;
+    out_color = vec4(time);
+}
+";
+        assert!(!reads_time(src));
+
+        let usage = utility_usage(src);
+        assert!(!usage.mouse && !usage.date);
+    }
+
+    #[test]
+    fn unparsed_shaders_are_assumed_to_read_everything() {
+        let src = "void main() { this is not glsl }";
+        assert!(reads_time(src));
+
+        let usage = utility_usage(src);
+        assert!(usage.mouse && usage.date);
+    }
+
+    const READS_FRAG_COORD: &str = "
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(gl_FragCoord.xy / 100.0, 0.0, 1.0);
+}
+";
+
+    #[test]
+    fn offsets_frag_coord_reads() {
+        let out = offset_frag_coord(READS_FRAG_COORD).unwrap().unwrap();
+
+        assert!(out.contains("push_constant"));
+        assert!(out.contains(TILE_OFFSET));
+        assert!(out.contains(&format!("{FRAG_COORD_FN}()")));
+
+        // the only read left is the one inside the helper
+        assert_eq!(out.matches(FRAG_COORD).count(), 1);
+        assert!(glsl::syntax::TranslationUnit::parse(&out).is_ok());
+    }
+
+    #[test]
+    fn leaves_shaders_without_frag_coord_alone() {
+        let src = "
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(1.0);
+}
+";
+        assert_eq!(offset_frag_coord(src), Ok(None));
+    }
+
+    #[test]
+    fn extends_an_existing_push_constant_block() {
+        let src = "
+layout(push_constant) uniform Push {
+    float strength;
+};
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(gl_FragCoord.x * strength);
+}
+";
+        let out = offset_frag_coord(src).unwrap().unwrap();
+
+        // one stage can only have one push constant block
+        assert_eq!(out.matches("push_constant").count(), 1);
+        assert!(!out.contains("AeTile"));
+
+        let unit = glsl::syntax::TranslationUnit::parse(&out).unwrap();
+        let fields: Vec<String> = unit
+            .0
+            .iter()
+            .find_map(|item| match item {
+                ExternalDeclaration::Declaration(Declaration::Block(block))
+                    if is_push_constant_block(block) =>
+                {
+                    Some(
+                        block
+                            .fields
+                            .iter()
+                            .flat_map(|field| field.identifiers.0.iter())
+                            .map(|ident| ident.ident.0.clone())
+                            .collect(),
+                    )
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(fields, ["strength", TILE_OFFSET]);
+    }
+}
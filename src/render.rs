@@ -1,6 +1,14 @@
-use tweak_shader::TextureDesc;
+use tweak_shader::{wgpu, TextureDesc};
 
 use super::*;
+use crate::annotations::{Annotations, Fallback, FloatUi, InputAnnotations, VectorKind};
+use crate::audio::{self, AudioCache, AudioKind};
+use crate::camera::{lights_to_floats, Camera, Light};
+use crate::host_context::HostContext;
+use crate::motion_blur::MotionBlurContext;
+use crate::supersample::{effective_factor, Filter, SupersampleContext};
+use crate::time_controls::TimeControls;
+use crate::u15_conversion::U16ConversionContext;
 
 // Runs the user shader, copying the results from the GPU to RAM.
 pub fn render(
@@ -17,74 +25,293 @@ pub fn render(
         ref mut ctx,
         u16_converter,
         fmt,
+        annotations,
+        simulation,
+        audio,
+        motion_blur,
+        supersample,
+        tileable,
+        param_layout,
         ..
     }) = local
     else {
         return Err(Error::Generic);
     };
-    let layers = load_parameters(ctx, state)?;
+    let fmt = *fmt;
+    let tiled = *tileable;
+    let param_layout = *param_layout;
+
+    let layout = extra
+        .pre_render_data::<FrameLayout>()
+        .cloned()
+        .unwrap_or_default();
 
     let cb = extra.callbacks();
 
-    let layer_iter = layers.iter().filter_map(|(name, index)| {
-        Some((
-            name.as_str(),
-            cb.checkout_layer_pixels(index.idx() as u32).ok()??,
-        ))
-    });
+    let Some(mut out_layer) = cb.checkout_output()? else {
+        return Ok(());
+    };
 
-    if let Some(converter) = u16_converter {
-        converter.prepare_cpu_layer_inputs(&global.device, &global.queue, layer_iter);
+    let width = out_layer.width() as u32;
+    let height = out_layer.height() as u32;
+    let stride = out_layer.buffer_stride() as u32;
 
-        let Some(mut out_layer) = cb.checkout_output()? else {
-            return Ok(());
-        };
+    let limits = global.device.limits();
+    let buffer_size = stride as u64 * height as u64;
+    if buffer_size > limits.max_buffer_size {
+        state.out_data.set_error_msg(&format!(
+            "Buffer size {} exceeds GPU max {}",
+            buffer_size, limits.max_buffer_size
+        ));
+        return Ok(());
+    }
+    if width > limits.max_texture_dimension_2d || height > limits.max_texture_dimension_2d {
+        state.out_data.set_error_msg(&format!(
+            "Texture {}x{} exceeds GPU max {}",
+            width, height, limits.max_texture_dimension_2d
+        ));
+        return Ok(());
+    }
 
-        ctx.update_resolution([out_layer.width() as f32, out_layer.height() as f32]);
-        converter.render_u15_to_cpu_buffer(&mut out_layer, &global.device, &global.queue, ctx);
-    } else {
-        for (name, layer) in layer_iter {
-            let real_fmt = *fmt;
-            ctx.load_texture(
-                name,
-                TextureDesc {
-                    width: layer.width() as u32,
-                    height: layer.height() as u32,
-                    stride: Some(layer.buffer_stride() as u32),
-                    data: layer.buffer(),
-                    format: real_fmt,
-                },
-                &global.device,
-                &global.queue,
-            );
+    let (requested_factor, filter) = supersample_settings(state)?;
+    let factor = effective_factor(&global.device, requested_factor, tiled, width, height);
+    let render_size = [width * factor, height * factor];
+
+    // pixel valued inputs are scaled into the pixels the shader renders
+    let pixel_scale = downsample_scale(state.in_data)[0] * factor as f32;
+
+    // the layers' audio may have changed since the last render
+    audio.invalidate();
+
+    let now = FrameTime::current(&state.in_data);
+    let layers = load_parameters(
+        ctx,
+        annotations,
+        param_layout,
+        state,
+        audio,
+        now,
+        pixel_scale,
+    )?;
+
+    // Uploads the checked out layers to the shader, reading
+    // a motion blur sub frame's checkouts when given one.
+    let upload_layers = |ctx: &mut tweak_shader::RenderContext,
+                         converter: Option<&mut U16ConversionContext>,
+                         layers: &[BoundLayer],
+                         sub_frame: Option<usize>| {
+        let layer_iter = layers.iter().filter_map(|layer| {
+            let id = match sub_frame {
+                Some(sub_frame) => sub_frame_checkout_id(sub_frame, layer.checkout_id),
+                None => layer.checkout_id,
+            };
+            Some((
+                layer.name.as_str(),
+                cb.checkout_layer_pixels(id as u32).ok()??,
+            ))
+        });
+
+        if let Some(converter) = converter {
+            // stale staging textures would shadow fallbacks for disconnected layers
+            converter.retain_staging_textures(|name| layers.iter().any(|l| l.name == name));
+            converter.prepare_cpu_layer_inputs(&global.device, &global.queue, layer_iter);
+            converter.bind_staging_textures(ctx);
+        } else {
+            for (name, layer) in layer_iter {
+                ctx.load_texture(
+                    name,
+                    TextureDesc {
+                        width: layer.width() as u32,
+                        height: layer.height() as u32,
+                        stride: Some(layer.buffer_stride() as u32),
+                        data: layer.buffer(),
+                        format: fmt,
+                    },
+                    &global.device,
+                    &global.queue,
+                );
+            }
         }
+    };
 
-        let Some(mut out_layer) = cb.checkout_output()? else {
-            return Ok(());
-        };
+    upload_layers(ctx, u16_converter.as_mut(), &layers, None);
+
+    host_uniforms::write_f32s(
+        ctx,
+        host_uniforms::EXPANSION,
+        &layout.expansion.map(|px| px as f32 * factor as f32),
+    );
+    host_uniforms::write_f32s(
+        ctx,
+        host_uniforms::DOWNSAMPLE,
+        &downsample_scale(state.in_data),
+    );
+    host_uniforms::write_f32s(
+        ctx,
+        host_uniforms::QUALITY,
+        &[if is_best_quality(state.in_data) {
+            1.0
+        } else {
+            0.0
+        }],
+    );
+    host_uniforms::write_f32s(
+        ctx,
+        host_uniforms::PREVIEW,
+        &[match is_final_render() {
+            Some(true) => 0.0,
+            Some(false) => 1.0,
+            None => -1.0,
+        }],
+    );
 
-        let width = out_layer.width() as u32;
-        let height = out_layer.height() as u32;
-        let stride = out_layer.buffer_stride() as u32;
+    load_comp_uniforms(ctx, state.in_data, now);
+
+    let par = state.in_data.pixel_aspect_ratio();
+    host_uniforms::write_f32s(
+        ctx,
+        host_uniforms::PIXEL_ASPECT,
+        &[par.num as f32 / par.den.max(1) as f32],
+    );
+
+    let image_inputs: Vec<String> = ctx
+        .iter_inputs()
+        .filter(|(name, ty)| param_util::is_image(ty) && !host_uniforms::is_host_uniform(name))
+        .map(|(name, _)| name.to_owned())
+        .collect();
+
+    for name in image_inputs {
+        let layer = layers.iter().find(|layer| layer.name == name);
+        let placement = layer.and_then(|layer| layout.placement(layer.checkout_id));
+        let present = layer.is_some_and(|layer| layer.present);
+
+        host_uniforms::write_input_info(ctx, &name, placement, present, factor as f32);
+    }
 
-        let limits = global.device.limits();
-        let buffer_size = stride as u64 * height as u64;
-        if buffer_size > limits.max_buffer_size {
+    if let Some(sim) = annotations.simulation.as_ref() {
+        if !crate::simulation::in_range(sim, now.frame()) {
             state.out_data.set_error_msg(&format!(
-                "Buffer size {} exceeds GPU max {}",
-                buffer_size, limits.max_buffer_size
+                "Simulations can run {} frames past their start frame",
+                crate::simulation::MAX_FRAMES
             ));
             return Ok(());
         }
-        if width > limits.max_texture_dimension_2d || height > limits.max_texture_dimension_2d {
-            state.out_data.set_error_msg(&format!(
-                "Texture {}x{} exceeds GPU max {}",
-                width, height, limits.max_texture_dimension_2d
-            ));
-            return Ok(());
+
+        simulation.advance_to(
+            now.frame(),
+            sim,
+            ctx,
+            annotations,
+            param_layout,
+            state,
+            audio,
+            fmt,
+            &global.device,
+            &global.queue,
+            render_size,
+            pixel_scale,
+        )?;
+    }
+
+    // the shader sees the supersampled frame, tiles included
+    ctx.update_resolution(render_size.map(|v| v as f32));
+
+    if !layout.sub_frames.is_empty() {
+        let blur = motion_blur
+            .get_or_insert_with(|| MotionBlurContext::new(&global.device, &global.queue, fmt));
+
+        blur.begin(&global.device, width, height);
+
+        let weight = 1.0 / layout.sub_frames.len() as f32;
+        for (sub_frame, time) in layout.sub_frames.iter().enumerate() {
+            let layers = load_parameters(
+                ctx,
+                annotations,
+                param_layout,
+                state,
+                audio,
+                *time,
+                pixel_scale,
+            )?;
+            upload_layers(ctx, u16_converter.as_mut(), &layers, Some(sub_frame));
+            // a moving camera or light blurs like the layers do
+            load_comp_uniforms(ctx, state.in_data, *time);
+            if factor > 1 {
+                let output = supersample
+                    .get_or_insert_with(|| {
+                        SupersampleContext::new(&global.device, &global.queue, fmt)
+                    })
+                    .render_to_output(
+                        ctx,
+                        &global.device,
+                        &global.queue,
+                        [width, height],
+                        factor,
+                        filter,
+                        tiled,
+                    );
+                blur.accumulate(&global.device, &global.queue, weight, output);
+            } else {
+                blur.accumulate_render(&global.device, &global.queue, weight, |target| {
+                    let mut enc = global.device.create_command_encoder(&Default::default());
+                    ctx.render(
+                        &global.queue,
+                        &global.device,
+                        &mut enc,
+                        target.create_view(&Default::default()),
+                        width,
+                        height,
+                    );
+                    global.queue.submit([enc.finish()]);
+                });
+            }
+        }
+
+        match (u16_converter, blur.result()) {
+            (Some(converter), Some(result)) => converter.render_texture_to_cpu_buffer(
+                &mut out_layer,
+                &global.device,
+                &global.queue,
+                result,
+            ),
+            _ => blur.resolve_to_slice(
+                &global.device,
+                &global.queue,
+                out_layer.buffer_mut(),
+                stride,
+            ),
         }
+    } else if factor > 1 {
+        let ss = supersample
+            .get_or_insert_with(|| SupersampleContext::new(&global.device, &global.queue, fmt));
+
+        let output = ss.render_to_output(
+            ctx,
+            &global.device,
+            &global.queue,
+            [width, height],
+            factor,
+            filter,
+            tiled,
+        );
 
-        ctx.update_resolution([width as f32, height as f32]);
+        match u16_converter {
+            Some(converter) => converter.render_texture_to_cpu_buffer(
+                &mut out_layer,
+                &global.device,
+                &global.queue,
+                output,
+            ),
+            None => ss.resolve_to_slice(
+                &global.device,
+                &global.queue,
+                out_layer.buffer_mut(),
+                stride,
+            ),
+        }
+    } else if let Some(converter) = u16_converter {
+        converter.render_u15_to_cpu_buffer(&mut out_layer, &global.device, &global.queue, ctx);
+    } else {
         ctx.render_to_slice(
             &global.queue,
             &global.device,
@@ -98,19 +325,668 @@ pub fn render(
     Ok(())
 }
 
-// Load params from AE to tweak shader
+// Checks out every layer the shader reads and sizes the output
+pub fn pre_render(
+    state: &mut super::PluginState,
+    instance: &mut super::Local,
+    extra: &mut PreRenderExtra,
+) -> Result<(), after_effects::Error> {
+    let in_data = state.in_data;
+    let mut req = extra.output_request();
+
+    let cb = extra.callbacks();
+
+    let now = FrameTime::current(&in_data);
+
+    if let Some(global) = state.global.as_init() {
+        instance.init_or_update(&global.device, &global.queue, extra.bit_depth().into());
+    }
+
+    let sub_frames = motion_blur_times(instance, in_data)?;
+
+    // Checks out every image input at `time` plus its time offset,
+    // `id_for` maps a checkout id to the one used at this time.
+    let checkout_inputs = |req: &ae_sys::PF_RenderRequest,
+                           time: FrameTime,
+                           id_for: &dyn Fn(i32) -> i32| {
+        let mut placements = Vec::new();
+
+        let Some(LocalInit {
+            ctx,
+            annotations,
+            param_layout,
+            ..
+        }) = instance.local_init.as_ref()
+        else {
+            return Ok::<_, Error>(placements);
+        };
+
+        let slots = param_util::input_slots(ctx, annotations, *param_layout);
+        for ((name, _), param_index) in ctx
+            .iter_inputs()
+            .zip(slots)
+            .filter(|((_, v), _)| param_util::is_image(v))
+            .filter_map(|(input, slot)| Some((input, slot?)))
+        {
+            let id_and_index = param_index.idx();
+
+            let offset = annotations
+                .input(name)
+                .and_then(|a| a.time_offset)
+                .map(|t| t.to_ae_time(time.step, time.scale))
+                .unwrap_or(0);
+
+            let offset_secs = offset as f32 / time.scale as f32;
+
+            // time shifted inputs follow the shader's retimed clock
+            let base = if offset != 0 {
+                TimeControls::checkout(in_data, time)?.retimed(time)
+            } else {
+                time
+            };
+
+            let checkout = cb.checkout_layer(
+                id_and_index,
+                id_for(id_and_index),
+                req,
+                base.time + offset,
+                time.step,
+                time.scale,
+            )?;
+
+            placements.push(InputPlacement::new(
+                id_for(id_and_index),
+                checkout.result_rect.into(),
+                pixel_aspect(&checkout.par),
+                offset_secs,
+            ));
+
+            // the input may end up bound to the effect's own layer, which
+            // is otherwise only checked out at the current time
+            if offset != 0 {
+                let main_id = id_for(main_layer_checkout_id(param_index));
+                let checkout =
+                    cb.checkout_layer(0, main_id, req, base.time + offset, time.step, time.scale)?;
+
+                placements.push(InputPlacement::new(
+                    main_id,
+                    checkout.result_rect.into(),
+                    pixel_aspect(&checkout.par),
+                    offset_secs,
+                ));
+            }
+        }
+
+        Ok(placements)
+    };
+
+    let mut inputs = checkout_inputs(&req, now, &|id| id)?;
+
+    for (sub_frame, time) in sub_frames.iter().enumerate() {
+        checkout_inputs(&req, *time, &|id| sub_frame_checkout_id(sub_frame, id))?;
+    }
+
+    req.field = ae_sys::PF_Field_FRAME as i32;
+    req.preserve_rgb_of_zero_alpha = 1;
+    req.channel_mask = ae_sys::PF_ChannelMask_ARGB as i32;
+
+    // We checkout once just to see what the max rect is :(
+    if let Ok(width_test) = cb.checkout_layer(
+        0,
+        INPUT_LAYER_CHECKOUT_ID.idx() - 1,
+        &req,
+        now.time,
+        now.step,
+        now.scale,
+    ) {
+        req.rect = width_test.max_result_rect;
+
+        let full_checkout = cb.checkout_layer(
+            0,
+            INPUT_LAYER_CHECKOUT_ID.idx(),
+            &req,
+            now.time,
+            now.step,
+            now.scale,
+        )?;
+
+        for (sub_frame, time) in sub_frames.iter().enumerate() {
+            cb.checkout_layer(
+                0,
+                sub_frame_checkout_id(sub_frame, INPUT_LAYER_CHECKOUT_ID.idx()),
+                &req,
+                time.time,
+                time.step,
+                time.scale,
+            )?;
+        }
+
+        let expansion = output_expansion(instance, in_data)?;
+
+        let mut rect: Rect = full_checkout.result_rect.into();
+        rect.left -= expansion[0];
+        rect.top -= expansion[1];
+        rect.right += expansion[2];
+        rect.bottom += expansion[3];
+
+        extra.set_result_rect(rect.into());
+        extra.set_max_result_rect(rect.into());
+        extra.set_returns_extra_pixels(true);
+
+        inputs.push(InputPlacement::new(
+            INPUT_LAYER_CHECKOUT_ID.idx(),
+            full_checkout.result_rect.into(),
+            pixel_aspect(&full_checkout.par),
+            0.0,
+        ));
+
+        let inputs = inputs.into_iter().map(|p| p.relative_to(rect)).collect();
+
+        extra.set_pre_render_data(FrameLayout {
+            expansion,
+            inputs,
+            sub_frames,
+        });
+    }
+
+    Ok(())
+}
+
+// The requested supersampling factor and filter
+fn supersample_settings(state: &super::PluginState) -> Result<(u32, Filter), Error> {
+    // popup values start at 1, which is off
+    let factor = state
+        .params
+        .get(ParamIdx::Supersampling)?
+        .as_popup()?
+        .value()
+        .clamp(1, 4) as u32;
+
+    let filter = match state
+        .params
+        .get(ParamIdx::SupersampleFilter)?
+        .as_popup()?
+        .value()
+    {
+        2 => Filter::Lanczos,
+        _ => Filter::Box,
+    };
+
+    Ok((factor, filter))
+}
+
+// Whether any frame can be motion blurred, the shutter is only known at
+// render time. Shaders with persistent state are never blurred, every sub
+// frame would advance their state.
+pub fn may_motion_blur(instance: &super::Local, in_data: InData) -> Result<bool, Error> {
+    let has_state = instance.local_init.as_ref().is_some_and(|init| {
+        init.annotations.simulation.is_some() || !init.annotations.persistent_targets.is_empty()
+    });
+
+    Ok(!has_state && motion_blur_samples(in_data)? >= 2)
+}
+
+fn motion_blur_samples(in_data: InData) -> Result<u32, Error> {
+    Ok(checkout_scalar(in_data, ParamIdx::MotionBlurSamples)?.unwrap_or(1.0) as u32)
+}
+
+// Sub frame times to accumulate, empty when motion blur is off. With the comp
+// shutter the layer's motion blur switch decides, AE reports a zero angle when
+// it is off.
+fn motion_blur_times(
+    instance: &super::Local,
+    in_data: InData,
+) -> Result<Vec<FrameTime>, after_effects::Error> {
+    if !may_motion_blur(instance, in_data)? {
+        return Ok(Vec::new());
+    }
+
+    let samples = motion_blur_samples(in_data)?;
+
+    let use_comp_shutter = checkout_scalar(in_data, ParamIdx::UseCompShutter)?.unwrap_or(1.0) > 0.0;
+
+    let (angle, phase) = if use_comp_shutter {
+        // 16.16 fixed point degrees
+        (
+            in_data.shutter_angle() as f32 / 65536.0,
+            in_data.shutter_phase() as f32 / 65536.0,
+        )
+    } else {
+        let angle = checkout_scalar(in_data, ParamIdx::ShutterAngle)?.unwrap_or(0.0);
+        (angle, -angle / 2.0)
+    };
+
+    if angle <= 0.0 {
+        return Ok(Vec::new());
+    }
+
+    let now = FrameTime::current(&in_data);
+
+    Ok((0..samples)
+        .map(|i| {
+            let degrees = phase + angle * (i as f32 + 0.5) / samples as f32;
+            now.offset_frames(degrees / 360.0)
+        })
+        .collect())
+}
+
+fn pixel_aspect(par: &ae_sys::PF_RationalScale) -> f32 {
+    if par.den == 0 {
+        1.0
+    } else {
+        par.num as f32 / par.den as f32
+    }
+}
+
+// Pixels to grow the output by on each side, [left, top, right, bottom],
+// scaled by the current downsample factor. This is the sum of the
+// instance's Expand Pixels param and the shader's `expand` pragma.
+pub fn output_expansion(
+    instance: &super::Local,
+    in_data: InData,
+) -> Result<[i32; 4], after_effects::Error> {
+    let Some(LocalInit {
+        ctx,
+        annotations,
+        param_layout,
+        ..
+    }) = instance.local_init.as_ref()
+    else {
+        return Ok([0; 4]);
+    };
+
+    let extra = checkout_scalar(in_data, ParamIdx::ExpandPixels)?.unwrap_or(0.0);
+
+    // inputs resolve to what the shader receives at full resolution
+    let now = FrameTime::current(&in_data);
+    let declared = match annotations.expansion.as_ref() {
+        Some(expansion) => expansion.resolve(|name| {
+            let index = param_util::slot_of(ctx, annotations, *param_layout, name)?;
+
+            let mut param =
+                ParamDef::checkout(in_data, index.idx(), now.time, now.step, now.scale, None)
+                    .ok()?;
+            let slider = match param.as_param_mut().ok()? {
+                Param::FloatSlider(fl) => Some(fl.value()),
+                _ => None,
+            };
+
+            match slider {
+                Some(slider) => {
+                    float_value(in_data, annotations.input(name), slider, index, now, 1.0).ok()
+                }
+                None => checkout_scalar(in_data, index).ok().flatten(),
+            }
+        }),
+        None => [0.0; 4],
+    };
+
+    let [scale_x, scale_y] = downsample_scale(in_data);
+
+    Ok([
+        ((declared[0] + extra) * scale_x).ceil() as i32,
+        ((declared[1] + extra) * scale_y).ceil() as i32,
+        ((declared[2] + extra) * scale_x).ceil() as i32,
+        ((declared[3] + extra) * scale_y).ceil() as i32,
+    ])
+}
+
+// Binds every audio input to the audio of its layer around `time`,
+// unless `cache` shows they already hold it.
+fn load_audio_inputs(
+    ctx: &mut tweak_shader::RenderContext,
+    in_data: InData,
+    global: &InnerGlobal,
+    annotations: &Annotations,
+    param_layout: ParamLayout,
+    time: FrameTime,
+    cache: &mut AudioCache,
+) {
+    let has_audio = ctx.iter_inputs().any(|(_, ty)| AudioKind::of(ty).is_some());
+    if !has_audio || cache.is_bound_at(time) {
+        return;
+    }
+
+    let slots = param_util::input_slots(ctx, annotations, param_layout);
+    let audio_inputs: Vec<_> = ctx
+        .iter_inputs()
+        .zip(slots)
+        .filter_map(|((name, ty), slot)| {
+            Some((
+                name.to_owned(),
+                AudioKind::of(ty)?,
+                audio::texture_width(ty),
+                slot?,
+            ))
+        })
+        .collect();
+
+    for (name, kind, width, index) in audio_inputs {
+        audio::load(
+            ctx,
+            in_data,
+            &global.device,
+            &global.queue,
+            &name,
+            kind,
+            width,
+            index.idx(),
+            time.seconds() as f64,
+        );
+    }
+}
+
+// The `mouse` utility field driven by the Mouse params. xy is the point,
+// zw is the point again with z negated while the mouse is up.
+fn mouse_at(in_data: InData, time: FrameTime, pixel_scale: f32) -> Result<[f32; 4], Error> {
+    let checkout = |index: ParamIdx| {
+        ParamDef::checkout(in_data, index.idx(), time.time, time.step, time.scale, None)
+    };
+
+    let mut point = checkout(ParamIdx::Mouse)?;
+    let point = match point.as_param_mut()? {
+        Param::Point(pt) => {
+            let (x, y) = pt.value();
+            [x as f32, y as f32]
+        }
+        _ => [0.0; 2],
+    };
+
+    let mut down = checkout(ParamIdx::MouseDown)?;
+    let down = matches!(down.as_param_mut()?, Param::CheckBox(cb) if cb.value());
+
+    Ok(mouse_uniform(point, down, pixel_scale))
+}
+
+// The Mouse point in layer pixels as the `mouse` field
+fn mouse_uniform(point: [f32; 2], down: bool, pixel_scale: f32) -> [f32; 4] {
+    let [x, y] = point.map(|v| v * pixel_scale);
+    [x, y, if down { x } else { -x }, -y]
+}
+
+fn is_best_quality(in_data: InData) -> bool {
+    matches!(in_data.quality(), Quality::Hi)
+}
+
+// True while the render queue is rendering, the only time AE tells us a frame is
+// final. None if the suite is unavailable, as in hosts other than AE.
+fn is_final_render() -> Option<bool> {
+    let state = aegp::suites::RenderQueue::new()
+        .and_then(|suite| suite.render_queue_state())
+        .ok()?;
+
+    Some(state == aegp::RenderQueueState::Rendering)
+}
+
+// The fraction of full resolution AE is rendering at, [x, y]
+fn downsample_scale(in_data: InData) -> [f32; 2] {
+    let ds_x = in_data.downsample_x();
+    let ds_y = in_data.downsample_y();
+    [
+        ds_x.num as f32 / ds_x.den as f32,
+        ds_y.num as f32 / ds_y.den as f32,
+    ]
+}
+
+// Hash of the values of `params` at `time`, layer params hash as nothing
+pub fn params_hash(in_data: InData, params: &[ParamIdx], time: FrameTime) -> Result<u64, Error> {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for index in params {
+        let mut param =
+            ParamDef::checkout(in_data, index.idx(), time.time, time.step, time.scale, None)?;
+
+        let value: Vec<f64> = match param.as_param_mut()? {
+            Param::FloatSlider(fl) => vec![fl.value()],
+            Param::Angle(an) => vec![an.value()],
+            Param::Slider(int) => vec![int.value() as f64],
+            Param::Popup(p) => vec![p.value() as f64],
+            Param::CheckBox(cb) => vec![cb.value() as i32 as f64],
+            Param::Point(pt) => {
+                let (x, y) = pt.value();
+                vec![x as f64, y as f64]
+            }
+            Param::Point3D(pt) => {
+                let (x, y, z) = pt.value();
+                vec![x, y, z]
+            }
+            Param::Color(co) => match co.float_value() {
+                Ok(val) => [val.red, val.green, val.blue].map(|c| c as f64).to_vec(),
+                Err(_) => {
+                    let val = co.value();
+                    [val.red, val.green, val.blue].map(|c| c as f64).to_vec()
+                }
+            },
+            _ => Vec::new(),
+        };
+
+        index.hash(&mut hasher);
+        for v in value {
+            v.to_bits().hash(&mut hasher);
+        }
+    }
+
+    Ok(hasher.finish())
+}
+
+// The value a float input's shader uniform gets from its slider, or from
+// its angle param with `ui=angle`. Pixel valued floats are scaled by `pixel_scale`.
+fn float_value(
+    in_data: InData,
+    input: Option<&InputAnnotations>,
+    slider: f64,
+    index: ParamIdx,
+    time: FrameTime,
+    pixel_scale: f32,
+) -> Result<f32, Error> {
+    let ui = input.and_then(|a| a.ui);
+    let shown = match ui {
+        Some(FloatUi::Angle) => {
+            checkout_scalar_at(in_data, param_util::angle_slot(index), time)?.unwrap_or(0.0) as f64
+        }
+        _ => slider,
+    };
+
+    let scale = if input.is_some_and(|a| a.in_pixels()) {
+        pixel_scale
+    } else {
+        1.0
+    };
+
+    Ok(ui.map_or(shown as f32, |ui| ui.from_shown(shown)) * scale)
+}
+
+// Fills the host context, camera and light blocks the shader
+// declares with the comp's state at `time`.
+fn load_comp_uniforms(ctx: &mut tweak_shader::RenderContext, in_data: InData, time: FrameTime) {
+    // AEGP suites are missing in other hosts, the block stays zeroed there
+    if host_uniforms::is_declared(ctx, host_uniforms::HOST_CONTEXT) {
+        if let Ok(host) = HostContext::collect(in_data, time) {
+            host_uniforms::write_f32s(ctx, host_uniforms::HOST_CONTEXT, &host.to_floats());
+        }
+    }
+
+    if host_uniforms::is_declared(ctx, host_uniforms::CAMERA) {
+        if let Ok(camera) = Camera::collect(in_data, time) {
+            host_uniforms::write_f32s(ctx, host_uniforms::CAMERA, &camera.to_floats());
+        }
+    }
+
+    if host_uniforms::is_declared(ctx, host_uniforms::LIGHTS) {
+        let lights = Light::collect_all(in_data, time).unwrap_or_default();
+        host_uniforms::write_f32s(ctx, host_uniforms::LIGHTS, &lights_to_floats(&lights));
+        host_uniforms::write_f32s(ctx, host_uniforms::LIGHT_COUNT, &[lights.len() as f32]);
+    }
+}
+
+// Reads a float, int or checkbox at the current time, outside of render
+// the param array is not checked out for us.
+fn checkout_scalar(in_data: InData, index: ParamIdx) -> Result<Option<f32>, Error> {
+    checkout_scalar_at(in_data, index, FrameTime::current(&in_data))
+}
+
+fn checkout_scalar_at(
+    in_data: InData,
+    index: ParamIdx,
+    time: FrameTime,
+) -> Result<Option<f32>, Error> {
+    let mut param =
+        ParamDef::checkout(in_data, index.idx(), time.time, time.step, time.scale, None)?;
+
+    let value = match param.as_param_mut()? {
+        Param::FloatSlider(fl) => Some(fl.value() as f32),
+        Param::Angle(an) => Some(an.value() as f32),
+        Param::Slider(int) => Some(int.value() as f32),
+        Param::CheckBox(cb) => Some(if cb.value() { 1.0 } else { 0.0 }),
+        _ => None,
+    };
+
+    Ok(value)
+}
+
+// Fills the raw bytes of vector inputs from their sliders or 3D point,
+// clamped to the pragma's min and max.
+fn load_vector_inputs(
+    ctx: &mut tweak_shader::RenderContext,
+    in_data: InData,
+    annotations: &Annotations,
+    time: FrameTime,
+) -> Result<(), Error> {
+    for (n, vector) in annotations.vectors.iter().enumerate() {
+        let Some(slots) = param_util::vector_slots(n, vector.kind) else {
+            continue;
+        };
+
+        let mut value = vector.default;
+        if vector.kind == VectorKind::Point3d {
+            let mut param = ParamDef::checkout(
+                in_data,
+                slots[0].idx(),
+                time.time,
+                time.step,
+                time.scale,
+                None,
+            )?;
+            if let Param::Point3D(p) = param.as_param_mut()? {
+                let (x, y, z) = p.value();
+                value = [x as f32, y as f32, z as f32, 0.0];
+            }
+        } else {
+            for (component, index) in value.iter_mut().zip(slots) {
+                if let Some(v) = checkout_scalar_at(in_data, index, time)? {
+                    *component = v;
+                }
+            }
+        }
+
+        let value = vector.clamp(value);
+        host_uniforms::write_f32s(ctx, &vector.name, &value[..vector.kind.components()]);
+    }
+
+    Ok(())
+}
+
+// What an image input is bound to this frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageBinding {
+    // the pixels of the layer checked out under `id`. `present` is
+    // false when the effect's layer stands in for an empty layer param.
+    Layer { id: i32, present: bool },
+    Fallback(Fallback),
+    Unbound,
+}
+
+// A layer checked out for an image input
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundLayer {
+    pub name: String,
+    pub checkout_id: i32,
+    // see `ImageBinding::Layer`
+    pub present: bool,
+}
+
+// Decides what an image input samples, `is_main_input` is true for the
+// first image input of an image filter, which always reads the effect's layer.
+// `time_shifted` inputs read the effect's layer through their own checkout.
+pub fn bind_image(
+    index: ParamIdx,
+    is_main_input: bool,
+    connected: bool,
+    time_shifted: bool,
+    fallback: Option<Fallback>,
+) -> ImageBinding {
+    let main_layer = |present| ImageBinding::Layer {
+        id: if time_shifted {
+            main_layer_checkout_id(index)
+        } else {
+            INPUT_LAYER_CHECKOUT_ID.idx()
+        },
+        present,
+    };
+
+    if is_main_input {
+        return main_layer(true);
+    }
+
+    if connected {
+        return ImageBinding::Layer {
+            id: index.idx(),
+            present: true,
+        };
+    }
+
+    match fallback {
+        Some(Fallback::MainInput) => main_layer(false),
+        Some(fallback) => ImageBinding::Fallback(fallback),
+        None => ImageBinding::Unbound,
+    }
+}
+
+// Generates the texture shown by a disconnected input, as
+// Rgba8Unorm in AE's ARGB channel order.
+fn fallback_pixels(fallback: Fallback) -> (u32, u32, Vec<u8>) {
+    const CHECKER_SIZE: u32 = 64;
+    const CHECKER_CELL: u32 = 8;
+
+    match fallback {
+        Fallback::Transparent => (1, 1, vec![0, 0, 0, 0]),
+        Fallback::Black => (1, 1, vec![255, 0, 0, 0]),
+        Fallback::White => (1, 1, vec![255, 255, 255, 255]),
+        Fallback::Checker => {
+            let mut data = Vec::with_capacity((CHECKER_SIZE * CHECKER_SIZE * 4) as usize);
+            for y in 0..CHECKER_SIZE {
+                for x in 0..CHECKER_SIZE {
+                    let dark = ((x / CHECKER_CELL) + (y / CHECKER_CELL)) % 2 == 0;
+                    let v = if dark { 102 } else { 153 };
+                    data.extend_from_slice(&[255, v, v, v]);
+                }
+            }
+            (CHECKER_SIZE, CHECKER_SIZE, data)
+        }
+        // bound as a layer, see `bind_image`
+        Fallback::MainInput => (1, 1, vec![0, 0, 0, 0]),
+    }
+}
+
+// Load params from AE to tweak shader, as they are at `time`. Floats with
+// `units=pixels` are multiplied by `pixel_scale`.
 pub fn load_parameters(
     ctx: &mut tweak_shader::RenderContext,
+    annotations: &Annotations,
+    param_layout: ParamLayout,
     state: &super::PluginState,
-) -> Result<Vec<(String, ParamIdx)>, after_effects::Error> {
+    audio: &mut AudioCache,
+    time: FrameTime,
+    pixel_scale: f32,
+) -> Result<Vec<BoundLayer>, after_effects::Error> {
     let in_data = state.in_data;
-    let current_time = in_data.current_time();
-    let current_frame = state.in_data.current_frame();
-    let current_delta = in_data.time_step();
-    let time_step = in_data.time_step();
-    let time_scale = in_data.time_scale();
+    let current_time = time.time;
+    let time_step = time.step;
+    let time_scale = time.scale;
     let mut non_null_images = Vec::new();
     let mut null_images = Vec::new();
+    let mut fallback_images = Vec::new();
 
     let is_image_filter = state
         .params
@@ -120,8 +996,23 @@ pub fn load_parameters(
 
     let mut first_image = true;
 
-    for (i, (name, mut input)) in ctx.iter_inputs_mut().enumerate() {
-        let index = param_util::index_from_mut(i, &mut input);
+    // points are read in full resolution layer pixels, AE can
+    // downsample x and y by different amounts.
+    let [downsample_x, downsample_y] = downsample_scale(in_data);
+    let point_scale = [pixel_scale, pixel_scale * downsample_y / downsample_x];
+    let layer_size = [in_data.width(), in_data.height()].map(|v| (v as f32).max(1.0));
+
+    let slots = param_util::input_slots(ctx, annotations, param_layout);
+    for ((name, mut input), slot) in ctx.iter_inputs_mut().zip(slots) {
+        // host uniforms have no param
+        let Some(index) = slot else {
+            continue;
+        };
+
+        // bound by `load_audio_inputs`
+        if AudioKind::of_variant(input.variant()).is_some() {
+            continue;
+        }
 
         let mut param = ParamDef::checkout(
             in_data,
@@ -140,22 +1031,40 @@ pub fn load_parameters(
                     } else {
                         tweak_shader::input_type::ShaderBool::False
                     };
+                } else if let Some(event) = input.as_event() {
+                    *event = cb.value() as u32;
                 }
             }
             Param::Color(co) => {
                 if let Some(color) = input.as_color() {
-                    let val = co.value();
-                    color.current = [
-                        val.red as f32 / 255.0,
-                        val.green as f32 / 255.0,
-                        val.blue as f32 / 255.0,
-                        val.alpha as f32 / 255.0,
-                    ];
+                    // float colors keep super whites, 8 bit is all older hosts have
+                    let [red, green, blue] = match co.float_value() {
+                        Ok(val) => [val.red, val.green, val.blue],
+                        Err(_) => {
+                            let val = co.value();
+                            [val.red, val.green, val.blue].map(|c| c as f32 / 255.0)
+                        }
+                    };
+
+                    let opacity =
+                        checkout_scalar_at(in_data, param_util::opacity_slot(index), time)?
+                            .unwrap_or(100.0);
+
+                    color.current = [red, green, blue, opacity / 100.0];
                 }
             }
             Param::FloatSlider(fl) => {
+                let value = float_value(
+                    in_data,
+                    annotations.input(name),
+                    fl.value(),
+                    index,
+                    time,
+                    pixel_scale,
+                )?;
+
                 if let Some(float) = input.as_float() {
-                    float.current = fl.value() as f32;
+                    float.current = value;
                 }
             }
             Param::Slider(int) => {
@@ -165,7 +1074,18 @@ pub fn load_parameters(
             }
             Param::Point(pt) => {
                 if let Some(point) = input.as_point() {
-                    point.current = pt.value().into();
+                    let (x, y) = pt.value();
+                    let input_annotations = annotations.input(name);
+                    let space = input_annotations.and_then(|a| a.space).unwrap_or_default();
+                    let value =
+                        space.from_layer([x as f32, y as f32], layer_size, annotations.y_up);
+
+                    // min and max are at full resolution like the param
+                    let value = match input_annotations {
+                        Some(a) => a.clamp_point(value),
+                        None => value,
+                    };
+                    point.current = space.to_render(value, point_scale);
                 }
             }
             Param::Popup(int) => {
@@ -173,23 +1093,34 @@ pub fn load_parameters(
                     if let Some(entry) = ount
                         .labels
                         .as_ref()
-                        .and_then(|l| l.get(int.value() as usize - 1))
-                        .map(|(_, v)| v)
+                        .and_then(|l| param_util::popup_value(l, int.value()))
                     {
-                        ount.value.current = *entry;
+                        ount.value.current = entry;
                     }
                 }
             }
             Param::Layer(l) => {
-                if first_image && is_image_filter {
-                    first_image = false;
-                    non_null_images.push((name.to_owned(), INPUT_LAYER_CHECKOUT_ID));
-                    continue;
-                }
-                if l.value().is_some() {
-                    non_null_images.push((name.to_owned(), index));
-                } else {
-                    null_images.push(name.to_owned());
+                let is_main_input = first_image && is_image_filter;
+                first_image = false;
+
+                let input_annotations = annotations.input(name);
+                let fallback = input_annotations.and_then(|a| a.fallback);
+                let time_shifted = input_annotations.is_some_and(|a| a.time_offset.is_some());
+
+                match bind_image(
+                    index,
+                    is_main_input,
+                    l.value().is_some(),
+                    time_shifted,
+                    fallback,
+                ) {
+                    ImageBinding::Layer { id, present } => non_null_images.push(BoundLayer {
+                        name: name.to_owned(),
+                        checkout_id: id,
+                        present,
+                    }),
+                    ImageBinding::Fallback(f) => fallback_images.push((name.to_owned(), f)),
+                    ImageBinding::Unbound => null_images.push(name.to_owned()),
                 }
             }
             _ => {}
@@ -200,21 +1131,156 @@ pub fn load_parameters(
         ctx.remove_texture(&image_name);
     }
 
-    let use_layer_time = state
-        .params
-        .get(ParamIdx::UseLayerTime)?
-        .as_checkbox()?
-        .value();
+    load_vector_inputs(ctx, in_data, annotations, time)?;
 
-    if !use_layer_time {
-        let time = state.params.get(ParamIdx::Time)?.as_float_slider()?.value();
-        ctx.update_time(time as f32);
-    } else {
-        ctx.update_time(current_time as f32 / time_scale as f32);
+    if let Some(global) = state.global.as_init() {
+        for (image_name, fallback) in fallback_images {
+            let (width, height, data) = fallback_pixels(fallback);
+            ctx.load_texture(
+                &image_name,
+                TextureDesc {
+                    width,
+                    height,
+                    stride: Some(width * 4),
+                    data: &data,
+                    format: wgpu::TextureFormat::Rgba8Unorm,
+                },
+                &global.device,
+                &global.queue,
+            );
+        }
     }
 
-    ctx.update_frame_count(current_frame as u32);
-    ctx.update_delta(current_delta as f32);
+    let controls = TimeControls::checkout(in_data, time)?;
+
+    if let Some(global) = state.global.as_init() {
+        load_audio_inputs(
+            ctx,
+            in_data,
+            global,
+            annotations,
+            param_layout,
+            controls.retimed(time),
+            audio,
+        );
+    }
+
+    ctx.update_time(controls.shader_time(time) as f32);
+    ctx.update_frame_count(controls.shader_frame(time) as u32);
+    ctx.update_delta(controls.shader_delta(time) as f32);
+    ctx.update_frame_rate(time_scale as f32 / time_step.max(1) as f32);
+
+    let date_source = state.params.get(ParamIdx::DateSource)?.as_popup()?.value();
+    ctx.update_date(if date_source == 2 {
+        date::wall_clock()
+    } else {
+        let start = state
+            .params
+            .get(ParamIdx::StartDate)?
+            .as_float_slider()?
+            .value();
+        // layer time outside AE, where there is no comp to convert to
+        let comp_seconds = host_util::comp_time(in_data, time)
+            .map(host_util::seconds)
+            .unwrap_or(time.seconds() as f64);
+        date::from_start_date(start as u32, comp_seconds)
+    });
+
+    ctx.update_mouse(mouse_at(in_data, time, pixel_scale)?);
 
     Ok(non_null_images)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mouse_negates_z_while_up() {
+        assert_eq!(
+            mouse_uniform([10.0, 20.0], true, 1.0),
+            [10.0, 20.0, 10.0, -20.0]
+        );
+        assert_eq!(
+            mouse_uniform([10.0, 20.0], false, 1.0),
+            [10.0, 20.0, -10.0, -20.0]
+        );
+    }
+
+    #[test]
+    fn mouse_scales_to_rendered_pixels() {
+        assert_eq!(
+            mouse_uniform([10.0, 20.0], true, 0.5),
+            [5.0, 10.0, 5.0, -10.0]
+        );
+        assert_eq!(
+            mouse_uniform([10.0, 20.0], false, 2.0),
+            [20.0, 40.0, -20.0, -40.0]
+        );
+    }
+
+    const INPUT: ParamIdx = ParamIdx::Dynamic(13);
+
+    #[test]
+    fn connected_layer_binds_its_own_checkout() {
+        assert_eq!(
+            bind_image(INPUT, false, true, false, Some(Fallback::Black)),
+            ImageBinding::Layer {
+                id: INPUT.idx(),
+                present: true
+            }
+        );
+    }
+
+    #[test]
+    fn empty_layer_picks_its_fallback() {
+        assert_eq!(
+            bind_image(INPUT, false, false, false, Some(Fallback::Transparent)),
+            ImageBinding::Fallback(Fallback::Transparent)
+        );
+        assert_eq!(
+            bind_image(INPUT, false, false, false, Some(Fallback::White)),
+            ImageBinding::Fallback(Fallback::White)
+        );
+        assert_eq!(
+            bind_image(INPUT, false, false, false, None),
+            ImageBinding::Unbound
+        );
+    }
+
+    #[test]
+    fn main_input_fallback_is_not_present() {
+        assert_eq!(
+            bind_image(INPUT, false, false, false, Some(Fallback::MainInput)),
+            ImageBinding::Layer {
+                id: INPUT_LAYER_CHECKOUT_ID.idx(),
+                present: false
+            }
+        );
+        assert_eq!(
+            bind_image(INPUT, false, false, true, Some(Fallback::MainInput)),
+            ImageBinding::Layer {
+                id: main_layer_checkout_id(INPUT),
+                present: false
+            }
+        );
+    }
+
+    #[test]
+    fn image_filter_main_input_reads_the_effect_layer() {
+        assert_eq!(
+            bind_image(INPUT, true, false, false, None),
+            ImageBinding::Layer {
+                id: INPUT_LAYER_CHECKOUT_ID.idx(),
+                present: true
+            }
+        );
+        assert_eq!(
+            bind_image(INPUT, true, true, true, None),
+            ImageBinding::Layer {
+                id: main_layer_checkout_id(INPUT),
+                present: true
+            }
+        );
+    }
+}
@@ -0,0 +1,20 @@
+#pragma input(image, name="sub_frame")
+layout(set = 0, binding = 0) uniform texture2D sub_frame;
+
+#pragma input(image, name="accum")
+layout(set = 0, binding = 1) uniform texture2D accum;
+
+#pragma input(float, name="weight", default=1.0)
+#pragma input(float, name="keep", default=0.0)
+layout(set = 0, binding = 2) uniform Accumulation {
+    float weight;
+    float keep;
+};
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    ivec2 pixel_coords = ivec2(gl_FragCoord.xy);
+    vec4 previous = texelFetch(accum, pixel_coords, 0) * keep;
+    out_color = previous + texelFetch(sub_frame, pixel_coords, 0) * weight;
+}
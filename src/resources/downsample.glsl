@@ -0,0 +1,61 @@
+#pragma input(image, name="input_image")
+layout(set = 0, binding = 0) uniform texture2D input_image;
+
+#pragma input(int, name="factor", default=2)
+#pragma input(int, name="lanczos", default=0)
+#pragma input(int, name="apron", default=0)
+layout(set = 0, binding = 1) uniform Downsample {
+    int factor;
+    int lanczos;
+    int apron;
+};
+
+layout(location = 0) out vec4 out_color;
+
+const float PI = 3.14159265359;
+
+float lanczos2(float x) {
+    x = abs(x);
+    if (x < 0.0001) {
+        return 1.0;
+    }
+    if (x >= 2.0) {
+        return 0.0;
+    }
+    float px = PI * x;
+    return 2.0 * sin(px) * sin(px * 0.5) / (px * px);
+}
+
+// filters a supersampled tile down by `factor`, the tile has
+// `apron` extra pixels on every side for the lanczos kernel.
+void main() {
+    ivec2 size = textureSize(input_image, 0);
+    ivec2 base = ivec2(gl_FragCoord.xy) * factor + apron;
+
+    if (lanczos == 0) {
+        vec4 sum = vec4(0.0);
+        for (int y = 0; y < factor; y++) {
+            for (int x = 0; x < factor; x++) {
+                sum += texelFetch(input_image, clamp(base + ivec2(x, y), ivec2(0), size - 1), 0);
+            }
+        }
+        out_color = sum / float(factor * factor);
+        return;
+    }
+
+    vec2 center = vec2(base) + float(factor) * 0.5;
+    int radius = 2 * factor;
+
+    vec4 sum = vec4(0.0);
+    float total = 0.0;
+    for (int y = -radius; y < factor + radius; y++) {
+        for (int x = -radius; x < factor + radius; x++) {
+            ivec2 texel = base + ivec2(x, y);
+            vec2 d = (vec2(texel) + 0.5 - center) / float(factor);
+            float w = lanczos2(d.x) * lanczos2(d.y);
+            sum += texelFetch(input_image, clamp(texel, ivec2(0), size - 1), 0) * w;
+            total += w;
+        }
+    }
+    out_color = sum / max(total, 0.0001);
+}
@@ -0,0 +1,9 @@
+#pragma input(image, name="input_image")
+layout(set = 0, binding = 0) uniform texture2D input_image;
+
+layout(location = 0) out vec4 out_color;
+
+// copies a float texture into the render context's format
+void main() {
+    out_color = texelFetch(input_image, ivec2(gl_FragCoord.xy), 0);
+}
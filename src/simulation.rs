@@ -0,0 +1,595 @@
+// Deterministic persistent passes. AE renders frames out of order, so a
+// shader that feeds back through persistent buffers would otherwise see
+// whatever frame happened to render last. With `#pragma simulation` the
+// instance pre rolls from the start frame to the requested one, keeping
+// checkpoints of the persistent buffers so it rarely has to start over.
+//
+// Layer inputs are not checked out for the pre rolled frames, they hold the
+// requested frame's pixels, time, frame index and params do advance.
+
+use std::collections::BTreeMap;
+
+use crate::annotations::{Annotations, FloatUi, Simulation};
+use crate::audio::AudioCache;
+use crate::param_util::{self, Variant};
+use crate::render::{load_parameters, params_hash};
+use crate::types::{FrameTime, ParamIdx, ParamLayout};
+use after_effects::{Error, InteractCallbacks};
+use tweak_shader::{
+    wgpu::{self, Device, Queue},
+    RenderContext,
+};
+
+// Checkpoints hold a copy of every persistent buffer, this bounds the vram spent on them
+const MAX_CHECKPOINTS: usize = 32;
+
+// Frames past the start frame a simulation can reach, ten minutes at 30 fps.
+// Bounds how long a single render can spend pre rolling.
+pub const MAX_FRAMES: i32 = 18_000;
+
+#[derive(Debug, Default)]
+pub struct SimulationCache {
+    // keyed by the frame whose render produced the state
+    checkpoints: BTreeMap<i32, Checkpoint>,
+    // hash of the params every frame from the start frame on was simulated
+    // with, a checkpoint is only reused while its frames still match.
+    history: Vec<u64>,
+    // the frame whose state is currently in the persistent buffers
+    live_frame: Option<i32>,
+    size: [u32; 2],
+    scratch: Option<wgpu::Texture>,
+}
+
+#[derive(Debug)]
+struct Checkpoint {
+    textures: Vec<(String, wgpu::Texture)>,
+}
+
+// Whether a simulation can reach `frame`, see `MAX_FRAMES`
+pub fn in_range(sim: &Simulation, frame: i32) -> bool {
+    frame - sim.start <= MAX_FRAMES
+}
+
+fn is_checkpoint_frame(sim: &Simulation, frame: i32) -> bool {
+    (frame - sim.start) % sim.checkpoint_interval as i32 == 0
+}
+
+impl SimulationCache {
+    pub fn invalidate(&mut self) {
+        self.checkpoints.clear();
+        self.history.clear();
+        self.live_frame = None;
+    }
+
+    // Brings the persistent buffers to the state they would have after
+    // rendering every frame from the start frame up to `frame - 1`, then
+    // reloads the params for the current time.
+    #[allow(clippy::too_many_arguments)]
+    pub fn advance_to(
+        &mut self,
+        frame: i32,
+        sim: &Simulation,
+        ctx: &mut RenderContext,
+        annotations: &Annotations,
+        param_layout: ParamLayout,
+        state: &crate::PluginState,
+        audio: &mut AudioCache,
+        fmt: wgpu::TextureFormat,
+        device: &Device,
+        queue: &Queue,
+        [width, height]: [u32; 2],
+        pixel_scale: f32,
+    ) -> Result<(), Error> {
+        let now = FrameTime::current(&state.in_data);
+        let frame_time = |f: i32| now.at_frame(f);
+
+        let params = simulated_params(ctx, annotations, param_layout);
+        let hash_at = |f: i32| params_hash(state.in_data, &params, frame_time(f));
+
+        if self.size != [width, height] {
+            self.invalidate();
+            self.size = [width, height];
+        }
+
+        let last = frame - 1;
+
+        if frame <= sim.start {
+            clear_persistent(ctx, annotations, queue);
+        } else if self.live_frame != Some(last) {
+            let first = match self.resume_point(sim.start, last, hash_at)? {
+                Some(checkpoint_frame) => {
+                    let live: &RenderContext = ctx;
+                    self.restore(
+                        checkpoint_frame,
+                        |name| live.get_texture(name),
+                        device,
+                        queue,
+                    );
+                    checkpoint_frame + 1
+                }
+                None => {
+                    clear_persistent(ctx, annotations, queue);
+                    sim.start
+                }
+            };
+
+            // the buffers hold no frame's state until the pre roll finishes
+            self.live_frame = None;
+
+            ctx.update_resolution([width as f32, height as f32]);
+
+            let interact = InteractCallbacks::new(state.in_data);
+
+            for f in first..=last {
+                // long pre rolls stay cancellable
+                interact.abort()?;
+
+                load_parameters(
+                    ctx,
+                    annotations,
+                    param_layout,
+                    state,
+                    audio,
+                    frame_time(f),
+                    pixel_scale,
+                )?;
+                self.record(sim.start, f, hash_at(f)?);
+                self.step(ctx, fmt, device, queue);
+
+                if is_checkpoint_frame(sim, f) {
+                    let live: &RenderContext = ctx;
+                    self.save(
+                        f,
+                        &annotations.persistent_targets,
+                        |name| live.get_texture(name),
+                        device,
+                        queue,
+                    );
+                }
+            }
+        }
+
+        load_parameters(
+            ctx,
+            annotations,
+            param_layout,
+            state,
+            audio,
+            now,
+            pixel_scale,
+        )?;
+        if frame >= sim.start {
+            self.record(sim.start, frame, hash_at(frame)?);
+        }
+        self.live_frame = Some(frame);
+
+        Ok(())
+    }
+
+    // The newest checkpoint at or before `last` whose frames all still have
+    // the params they were simulated with, `hash_at` hashes a frame's params.
+    fn resume_point<E>(
+        &mut self,
+        start: i32,
+        last: i32,
+        mut hash_at: impl FnMut(i32) -> Result<u64, E>,
+    ) -> Result<Option<i32>, E> {
+        let newest = |checkpoints: &BTreeMap<i32, Checkpoint>| {
+            checkpoints.range(..=last).next_back().map(|(f, _)| *f)
+        };
+
+        let Some(checked_up_to) = newest(&self.checkpoints) else {
+            return Ok(None);
+        };
+
+        for f in start..=checked_up_to {
+            let recorded = self.history.get((f - start) as usize).copied();
+            if recorded != Some(hash_at(f)?) {
+                self.forget_from(start, f);
+                break;
+            }
+        }
+
+        Ok(newest(&self.checkpoints))
+    }
+
+    // Notes the params `frame` was simulated with. Different params than
+    // last time invalidate every later frame's state.
+    fn record(&mut self, start: i32, frame: i32, hash: u64) {
+        let index = (frame - start) as usize;
+        if self.history.get(index) == Some(&hash) {
+            return;
+        }
+
+        self.forget_from(start, frame);
+        if self.history.len() == index {
+            self.history.push(hash);
+        }
+    }
+
+    // Drops the history and checkpoints of `frame` and everything after it
+    fn forget_from(&mut self, start: i32, frame: i32) {
+        self.history.truncate((frame - start).max(0) as usize);
+        self.checkpoints.retain(|f, _| *f < frame);
+    }
+
+    // Renders one frame, discarding the output
+    fn step(
+        &mut self,
+        ctx: &mut RenderContext,
+        fmt: wgpu::TextureFormat,
+        device: &Device,
+        queue: &Queue,
+    ) {
+        let [width, height] = self.size;
+
+        let scratch = match self.scratch.take() {
+            Some(tex) if tex.width() == width && tex.height() == height && tex.format() == fmt => {
+                tex
+            }
+            _ => device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("simulation scratch"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: fmt,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                view_formats: &[],
+            }),
+        };
+
+        let mut enc = device.create_command_encoder(&Default::default());
+        ctx.render(
+            queue,
+            device,
+            &mut enc,
+            scratch.create_view(&Default::default()),
+            width,
+            height,
+        );
+        queue.submit([enc.finish()]);
+
+        self.scratch = Some(scratch);
+    }
+
+    // Copies the persistent buffers named `targets`, `live` looks them up
+    fn save<'a>(
+        &mut self,
+        frame: i32,
+        targets: &[String],
+        live: impl Fn(&str) -> Option<&'a wgpu::Texture>,
+        device: &Device,
+        queue: &Queue,
+    ) {
+        let mut enc = device.create_command_encoder(&Default::default());
+
+        let textures = targets
+            .iter()
+            .filter_map(|name| {
+                let live = live(name)?;
+                let copy = device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("simulation checkpoint"),
+                    size: live.size(),
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: live.format(),
+                    usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+                    view_formats: &[],
+                });
+                enc.copy_texture_to_texture(
+                    live.as_image_copy(),
+                    copy.as_image_copy(),
+                    live.size(),
+                );
+                Some((name.clone(), copy))
+            })
+            .collect();
+
+        queue.submit([enc.finish()]);
+
+        if self.checkpoints.len() >= MAX_CHECKPOINTS {
+            // drop the checkpoint farthest from where we are
+            let farthest = self
+                .checkpoints
+                .keys()
+                .max_by_key(|f| (*f - frame).abs())
+                .copied();
+            if let Some(farthest) = farthest {
+                self.checkpoints.remove(&farthest);
+            }
+        }
+
+        self.checkpoints.insert(frame, Checkpoint { textures });
+    }
+
+    // Copies a checkpoint back into the persistent buffers `live` looks up
+    fn restore<'a>(
+        &self,
+        frame: i32,
+        live: impl Fn(&str) -> Option<&'a wgpu::Texture>,
+        device: &Device,
+        queue: &Queue,
+    ) {
+        let Some(checkpoint) = self.checkpoints.get(&frame) else {
+            return;
+        };
+
+        let mut enc = device.create_command_encoder(&Default::default());
+        for (name, saved) in checkpoint.textures.iter() {
+            if let Some(live) = live(name) {
+                if live.size() == saved.size() {
+                    enc.copy_texture_to_texture(
+                        saved.as_image_copy(),
+                        live.as_image_copy(),
+                        saved.size(),
+                    );
+                }
+            }
+        }
+        queue.submit([enc.finish()]);
+    }
+}
+
+// Zeroes every persistent buffer, the state before the start frame
+fn clear_persistent(ctx: &RenderContext, annotations: &Annotations, queue: &Queue) {
+    for name in annotations.persistent_targets.iter() {
+        let Some(tex) = ctx.get_texture(name) else {
+            continue;
+        };
+
+        let bytes_per_pixel = tex.format().block_copy_size(None).unwrap_or(16);
+        let row = tex.width() * bytes_per_pixel;
+        let zeros = vec![0u8; (row * tex.height()) as usize];
+
+        queue.write_texture(
+            tex.as_image_copy(),
+            &zeros,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(row),
+                rows_per_image: Some(tex.height()),
+            },
+            tex.size(),
+        );
+    }
+}
+
+// Every param the shader's frames are rendered from, layers aside. Layer
+// inputs are not checked out while pre rolling.
+fn simulated_params(
+    ctx: &RenderContext,
+    annotations: &Annotations,
+    param_layout: ParamLayout,
+) -> Vec<ParamIdx> {
+    let slots = param_util::input_slots(ctx, annotations, param_layout);
+
+    let mut params = Vec::new();
+    for ((name, ty), slot) in ctx.iter_inputs().zip(slots) {
+        let Some(index) = slot else {
+            continue;
+        };
+
+        match Variant::of(ty) {
+            Some(Variant::Image) | None => {}
+            Some(Variant::Color) => {
+                params.extend([index, param_util::opacity_slot(index)]);
+            }
+            Some(Variant::Float) => {
+                params.push(index);
+                if annotations.input(name).and_then(|a| a.ui) == Some(FloatUi::Angle) {
+                    params.push(param_util::angle_slot(index));
+                }
+            }
+            Some(_) => params.push(index),
+        }
+    }
+
+    for (n, vector) in annotations.vectors.iter().enumerate() {
+        params.extend(param_util::vector_slots(n, vector.kind).unwrap_or_default());
+    }
+
+    // the clock, date and mouse of the utility block, AE does not
+    // report changes to these through UserChangedParam.
+    params.extend([
+        ParamIdx::UseLayerTime,
+        ParamIdx::TimeSource,
+        ParamIdx::Time,
+        ParamIdx::TimeOffset,
+        ParamIdx::PlaybackSpeed,
+        ParamIdx::LoopDuration,
+        ParamIdx::DateSource,
+        ParamIdx::StartDate,
+        ParamIdx::Mouse,
+        ParamIdx::MouseDown,
+    ]);
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIM: Simulation = Simulation {
+        start: 10,
+        checkpoint_interval: 5,
+    };
+
+    // a cache that simulated frames `SIM.start..=last` with `hash`,
+    // checkpointing like `advance_to` does
+    fn simulated(last: i32, hash: impl Fn(i32) -> u64) -> SimulationCache {
+        let mut cache = SimulationCache::default();
+        for f in SIM.start..=last {
+            cache.record(SIM.start, f, hash(f));
+            if is_checkpoint_frame(&SIM, f) {
+                cache.checkpoints.insert(
+                    f,
+                    Checkpoint {
+                        textures: Vec::new(),
+                    },
+                );
+            }
+        }
+        cache
+    }
+
+    fn resume(cache: &mut SimulationCache, last: i32, hash: impl Fn(i32) -> u64) -> Option<i32> {
+        cache
+            .resume_point(SIM.start, last, |f| Ok::<_, ()>(hash(f)))
+            .unwrap()
+    }
+
+    #[test]
+    fn checkpoints_every_interval_from_the_start() {
+        let frames: Vec<i32> = (10..=30)
+            .filter(|f| is_checkpoint_frame(&SIM, *f))
+            .collect();
+        assert_eq!(frames, [10, 15, 20, 25, 30]);
+    }
+
+    #[test]
+    fn frames_past_the_limit_are_out_of_range() {
+        assert!(in_range(&SIM, SIM.start));
+        assert!(in_range(&SIM, SIM.start + MAX_FRAMES));
+        assert!(!in_range(&SIM, SIM.start + MAX_FRAMES + 1));
+    }
+
+    #[test]
+    fn resumes_from_the_newest_checkpoint_before_the_frame() {
+        let mut cache = simulated(40, |_| 7);
+
+        assert_eq!(resume(&mut cache, 33, |_| 7), Some(30));
+        assert_eq!(resume(&mut cache, 30, |_| 7), Some(30));
+        assert_eq!(resume(&mut cache, 12, |_| 7), Some(10));
+        assert_eq!(resume(&mut cache, 9, |_| 7), None);
+    }
+
+    #[test]
+    fn a_change_before_a_checkpoint_invalidates_it() {
+        let mut cache = simulated(40, |_| 7);
+
+        // a keyframe moved at frame 22, between the checkpoints at 20 and 25
+        let edited = |f: i32| if f >= 22 { 8 } else { 7 };
+        assert_eq!(resume(&mut cache, 38, edited), Some(20));
+
+        assert_eq!(
+            cache.checkpoints.keys().copied().collect::<Vec<_>>(),
+            [10, 15, 20]
+        );
+        assert_eq!(cache.history.len(), 22 - SIM.start as usize);
+    }
+
+    #[test]
+    fn a_change_after_the_frame_keeps_earlier_checkpoints() {
+        let mut cache = simulated(40, |_| 7);
+
+        let edited = |f: i32| if f >= 36 { 8 } else { 7 };
+        assert_eq!(resume(&mut cache, 34, edited), Some(30));
+        assert!(cache.checkpoints.contains_key(&35));
+    }
+
+    #[test]
+    fn recording_new_params_forgets_later_frames() {
+        let mut cache = simulated(40, |_| 7);
+
+        cache.record(SIM.start, 27, 7);
+        assert_eq!(cache.checkpoints.len(), 7);
+
+        cache.record(SIM.start, 27, 9);
+        assert_eq!(
+            cache.checkpoints.keys().copied().collect::<Vec<_>>(),
+            [10, 15, 20, 25]
+        );
+        assert_eq!(cache.history.len(), 27 - SIM.start as usize + 1);
+        assert_eq!(cache.history.last(), Some(&9));
+    }
+
+    fn test_device() -> Option<(Device, Queue)> {
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&Default::default()))?;
+        pollster::block_on(adapter.request_device(&Default::default(), None)).ok()
+    }
+
+    fn state_texture(device: &Device, queue: &Queue, value: u8) -> wgpu::Texture {
+        let texture = device.create_texture(&crate::texture_util::target_desc(
+            64,
+            1,
+            wgpu::TextureFormat::Rgba8Unorm,
+        ));
+        queue.write_texture(
+            texture.as_image_copy(),
+            &[value; 256],
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(256),
+                rows_per_image: Some(1),
+            },
+            texture.size(),
+        );
+        texture
+    }
+
+    fn read_back(device: &Device, queue: &Queue, texture: &wgpu::Texture) -> Vec<u8> {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 256,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut enc = device.create_command_encoder(&Default::default());
+        enc.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(256),
+                    rows_per_image: Some(1),
+                },
+            },
+            texture.size(),
+        );
+        queue.submit([enc.finish()]);
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| ());
+        device.poll(wgpu::Maintain::Wait);
+        let bytes = slice.get_mapped_range().to_vec();
+        buffer.unmap();
+        bytes
+    }
+
+    #[test]
+    fn restores_the_state_a_checkpoint_saved() {
+        // needs a GPU, nothing to check without one
+        let Some((device, queue)) = test_device() else {
+            return;
+        };
+
+        let targets = ["state".to_owned()];
+        let mut cache = SimulationCache::default();
+
+        let live = state_texture(&device, &queue, 3);
+        cache.save(15, &targets, |_| Some(&live), &device, &queue);
+
+        let overwritten = state_texture(&device, &queue, 200);
+        cache.restore(15, |_| Some(&overwritten), &device, &queue);
+        assert!(read_back(&device, &queue, &overwritten)
+            .iter()
+            .all(|b| *b == 3));
+
+        // a frame without a checkpoint leaves the buffers alone
+        let untouched = state_texture(&device, &queue, 200);
+        cache.restore(20, |_| Some(&untouched), &device, &queue);
+        assert!(read_back(&device, &queue, &untouched)
+            .iter()
+            .all(|b| *b == 200));
+    }
+}
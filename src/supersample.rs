@@ -0,0 +1,306 @@
+use crate::host_uniforms;
+use crate::preprocessing::TILE_OFFSET;
+use crate::texture_util::target_desc;
+use tweak_shader::{
+    wgpu::{self, Device, Queue},
+    RenderContext,
+};
+
+// Largest supersampled tile edge, in pixels. Bounds the vram
+// a supersampled frame costs regardless of the output size.
+const MAX_TILE_SIZE: u32 = 4096;
+
+// Largest untiled supersampled frame, in pixels. Untiled frames are held
+// whole, this keeps them within a few tiles' worth of vram.
+const MAX_UNTILED_PIXELS: u64 = MAX_TILE_SIZE as u64 * MAX_TILE_SIZE as u64 * 4;
+
+// Filtered pixels are kept as floats until they are written out, so the
+// negative lobes of the lanczos filter are not clipped between passes.
+const FILTERED_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Box,
+    Lanczos,
+}
+
+impl Filter {
+    // low res pixels of neighbourhood the filter reads past a tile's edge
+    fn radius(self) -> u32 {
+        match self {
+            Filter::Box => 0,
+            Filter::Lanczos => 2,
+        }
+    }
+}
+
+// Renders the main shader at a multiple of the output size and filters
+// it back down. Shaders that read `gl_FragCoord` and declare no passes are
+// rendered in tiles. Shaders with passes, and shaders that never read
+// `gl_FragCoord`, are rendered in one piece with the factor reduced until
+// it fits the GPU's limits.
+#[derive(Debug)]
+pub struct SupersampleContext {
+    downsample_ctx: RenderContext,
+    // converts the filtered frame into the main context's format
+    // and copies it into a cpu buffer
+    resolve_ctx: RenderContext,
+    fmt: wgpu::TextureFormat,
+    // the main shader renders a tile here, in the main context's format
+    // since its pipeline writes it
+    tile_texture: Option<wgpu::Texture>,
+    // a filtered tile, copied into `output`
+    filtered_texture: Option<wgpu::Texture>,
+    // the filtered frame, in `FILTERED_FORMAT`
+    output: Option<wgpu::Texture>,
+}
+
+impl SupersampleContext {
+    pub fn new(device: &Device, queue: &Queue, fmt: wgpu::TextureFormat) -> Self {
+        Self {
+            downsample_ctx: RenderContext::new(
+                include_str!("./resources/downsample.glsl"),
+                FILTERED_FORMAT,
+                device,
+                queue,
+            )
+            .expect("downsample context broken"),
+            resolve_ctx: RenderContext::new(
+                include_str!("./resources/resolve.glsl"),
+                fmt,
+                device,
+                queue,
+            )
+            .expect("resolve context broken"),
+            fmt,
+            tile_texture: None,
+            filtered_texture: None,
+            output: None,
+        }
+    }
+
+    // Renders `main_render_ctx` supersampled by `factor` into `target`, a
+    // `FILTERED_FORMAT` texture. The caller sets the resolution uniform to
+    // the supersampled size first.
+    #[allow(clippy::too_many_arguments)]
+    fn render(
+        &mut self,
+        main_render_ctx: &mut RenderContext,
+        device: &Device,
+        queue: &Queue,
+        target: &wgpu::Texture,
+        factor: u32,
+        filter: Filter,
+        tiled: bool,
+    ) {
+        let width = target.width();
+        let height = target.height();
+        // untiled renders have no neighbours to read past their edges
+        let apron = if tiled { filter.radius() * factor } else { 0 };
+
+        let max_tile = MAX_TILE_SIZE.min(device.limits().max_texture_dimension_2d);
+        let (tile_width, tile_height) = if tiled {
+            let edge = (max_tile.saturating_sub(apron * 2) / factor).max(1);
+            (edge.min(width), edge.min(height))
+        } else {
+            (width, height)
+        };
+
+        let tile_size = [
+            tile_width * factor + apron * 2,
+            tile_height * factor + apron * 2,
+        ];
+
+        let tile = reuse_or_create(&mut self.tile_texture, device, tile_size, self.fmt);
+        let filtered = reuse_or_create(
+            &mut self.filtered_texture,
+            device,
+            [tile_width, tile_height],
+            FILTERED_FORMAT,
+        );
+
+        set_int(&mut self.downsample_ctx, "factor", factor as i32);
+        set_int(
+            &mut self.downsample_ctx,
+            "lanczos",
+            (filter == Filter::Lanczos) as i32,
+        );
+        set_int(&mut self.downsample_ctx, "apron", apron as i32);
+        self.downsample_ctx.load_shared_texture(tile, "input_image");
+
+        for top in (0..height).step_by(tile_height as usize) {
+            for left in (0..width).step_by(tile_width as usize) {
+                let offset = [
+                    (left * factor) as f32 - apron as f32,
+                    (top * factor) as f32 - apron as f32,
+                ];
+                host_uniforms::write_f32s(main_render_ctx, TILE_OFFSET, &offset);
+
+                let mut enc = device.create_command_encoder(&Default::default());
+                main_render_ctx.render(
+                    queue,
+                    device,
+                    &mut enc,
+                    tile.create_view(&Default::default()),
+                    tile_size[0],
+                    tile_size[1],
+                );
+                self.downsample_ctx.render(
+                    queue,
+                    device,
+                    &mut enc,
+                    filtered.create_view(&Default::default()),
+                    tile_width,
+                    tile_height,
+                );
+
+                // edge tiles are clipped to the target
+                enc.copy_texture_to_texture(
+                    filtered.as_image_copy(),
+                    wgpu::ImageCopyTexture {
+                        texture: target,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d {
+                            x: left,
+                            y: top,
+                            z: 0,
+                        },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    wgpu::Extent3d {
+                        width: tile_width.min(width - left),
+                        height: tile_height.min(height - top),
+                        depth_or_array_layers: 1,
+                    },
+                );
+                queue.submit([enc.finish()]);
+            }
+        }
+
+        host_uniforms::write_f32s(main_render_ctx, TILE_OFFSET, &[0.0, 0.0]);
+    }
+
+    // Renders into an internal output sized float texture, which the
+    // caller writes out with `resolve_to_slice` or a u16 converter.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_to_output(
+        &mut self,
+        main_render_ctx: &mut RenderContext,
+        device: &Device,
+        queue: &Queue,
+        [width, height]: [u32; 2],
+        factor: u32,
+        filter: Filter,
+        tiled: bool,
+    ) -> &wgpu::Texture {
+        let mut output = self.output.take();
+        reuse_or_create(&mut output, device, [width, height], FILTERED_FORMAT);
+        let output = output.expect("output texture was just created");
+
+        self.render(
+            main_render_ctx,
+            device,
+            queue,
+            &output,
+            factor,
+            filter,
+            tiled,
+        );
+
+        self.output.insert(output)
+    }
+
+    // Writes the internal output texture to a CPU buffer
+    pub fn resolve_to_slice(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        out: &mut [u8],
+        stride: u32,
+    ) {
+        let Some(output) = self.output.as_ref() else {
+            return;
+        };
+
+        self.resolve_ctx.load_shared_texture(output, "input_image");
+        self.resolve_ctx.render_to_slice(
+            queue,
+            device,
+            output.width(),
+            output.height(),
+            out,
+            Some(stride),
+        );
+    }
+}
+
+// The factor actually used for a `width` x `height` output,
+// untiled renders must fit in a single texture.
+pub fn effective_factor(device: &Device, factor: u32, tiled: bool, width: u32, height: u32) -> u32 {
+    if tiled {
+        return factor;
+    }
+
+    untiled_factor(
+        factor,
+        device.limits().max_texture_dimension_2d,
+        width,
+        height,
+    )
+}
+
+// The largest factor up to `factor` whose frame fits the texture
+// size limit and the untiled pixel budget.
+fn untiled_factor(factor: u32, max_dimension: u32, width: u32, height: u32) -> u32 {
+    (1..=factor)
+        .rev()
+        .find(|f| {
+            let [w, h] = [width, height].map(|v| v as u64 * *f as u64);
+            w <= max_dimension as u64 && h <= max_dimension as u64 && w * h <= MAX_UNTILED_PIXELS
+        })
+        .unwrap_or(1)
+}
+
+fn set_int(ctx: &mut RenderContext, name: &str, value: i32) {
+    if let Some(mut input) = ctx.get_input_mut(name) {
+        if let Some(int) = input.as_int() {
+            int.value.current = value;
+        }
+    }
+}
+
+fn reuse_or_create<'a>(
+    slot: &'a mut Option<wgpu::Texture>,
+    device: &Device,
+    [width, height]: [u32; 2],
+    format: wgpu::TextureFormat,
+) -> &'a wgpu::Texture {
+    if slot
+        .as_ref()
+        .is_some_and(|t| t.width() == width && t.height() == height && t.format() == format)
+    {
+        return slot.as_ref().expect("checked above");
+    }
+
+    slot.insert(device.create_texture(&target_desc(width, height, format)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untiled_factors_fit_the_texture_limit() {
+        assert_eq!(untiled_factor(4, 8192, 1920, 1080), 4);
+        assert_eq!(untiled_factor(4, 8192, 3000, 1000), 2);
+        assert_eq!(untiled_factor(4, 8192, 9000, 100), 1);
+    }
+
+    #[test]
+    fn untiled_factors_fit_the_pixel_budget() {
+        // 16k textures allow 4x of a 4k frame, the budget does not
+        assert_eq!(untiled_factor(4, 16384, 3840, 2160), 2);
+        assert_eq!(untiled_factor(3, 16384, 4096, 4096), 2);
+        assert_eq!(untiled_factor(2, 16384, 8192, 8192), 1);
+    }
+}
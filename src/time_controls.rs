@@ -0,0 +1,254 @@
+// The time the shader sees. Layer or comp time is offset, scaled by the
+// playback speed and wrapped by the loop duration, or replaced entirely by
+// the manual Time param.
+
+use crate::host_util::{comp_time, seconds};
+use crate::types::{FrameTime, ParamIdx};
+use after_effects::{Error, InData, Param, ParamDef};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    Layer,
+    Comp,
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeControls {
+    pub source: TimeSource,
+    // seconds, added after the speed is applied
+    pub offset: f64,
+    pub speed: f64,
+    // seconds, 0 does not loop
+    pub loop_duration: f64,
+    // seconds, the Time param
+    pub manual: f64,
+    // seconds from layer time to comp time
+    comp_offset: f64,
+}
+
+impl TimeControls {
+    // Reads the controls as they are at `time`
+    pub fn checkout(in_data: InData, time: FrameTime) -> Result<Self, Error> {
+        let value = |index: ParamIdx| -> Result<f64, Error> {
+            let mut param =
+                ParamDef::checkout(in_data, index.idx(), time.time, time.step, time.scale, None)?;
+
+            Ok(match param.as_param_mut()? {
+                Param::FloatSlider(fl) => fl.value(),
+                Param::Popup(p) => p.value() as f64,
+                Param::CheckBox(cb) => cb.value() as i32 as f64,
+                _ => 0.0,
+            })
+        };
+
+        // projects from before the Time Source popup used the checkbox for
+        // manual time, changing the popup checks it again.
+        let use_layer_time = value(ParamIdx::UseLayerTime)? != 0.0;
+
+        let source = match value(ParamIdx::TimeSource)? as i32 {
+            _ if !use_layer_time => TimeSource::Manual,
+            2 => TimeSource::Comp,
+            3 => TimeSource::Manual,
+            _ => TimeSource::Layer,
+        };
+
+        let comp_offset = match source {
+            TimeSource::Comp => comp_time_offset(in_data, time).unwrap_or(0.0),
+            _ => 0.0,
+        };
+
+        Ok(Self {
+            source,
+            offset: value(ParamIdx::TimeOffset)?,
+            speed: value(ParamIdx::PlaybackSpeed)?,
+            loop_duration: value(ParamIdx::LoopDuration)?,
+            manual: value(ParamIdx::Time)?,
+            comp_offset,
+        })
+    }
+
+    // True when the shader sees plain layer time
+    pub fn is_identity(&self) -> bool {
+        self.source == TimeSource::Layer
+            && self.offset == 0.0
+            && self.speed == 1.0
+            && self.loop_duration <= 0.0
+    }
+
+    // The shader's time in seconds at layer time `time`
+    pub fn shader_time(&self, time: FrameTime) -> f64 {
+        let retimed = self.unlooped_time(time);
+
+        if self.loop_duration > 0.0 && self.source != TimeSource::Manual {
+            retimed.rem_euclid(self.loop_duration)
+        } else {
+            retimed
+        }
+    }
+
+    // Seconds of shader time from the frame before `time` to `time`. A
+    // loop wrapping around between them does not count as a jump back.
+    pub fn shader_delta(&self, time: FrameTime) -> f64 {
+        self.unlooped_time(time) - self.unlooped_time(time.offset_frames(-1.0))
+    }
+
+    fn unlooped_time(&self, time: FrameTime) -> f64 {
+        let base = match self.source {
+            TimeSource::Manual => return self.manual,
+            TimeSource::Layer => time.time as f64 / time.scale as f64,
+            TimeSource::Comp => time.time as f64 / time.scale as f64 + self.comp_offset,
+        };
+
+        self.offset + base * self.speed
+    }
+
+    // The shader's frame index at layer time `time`. Manual time keeps
+    // the layer's frame so persistent shaders still advance.
+    pub fn shader_frame(&self, time: FrameTime) -> i32 {
+        match self.source {
+            _ if self.is_identity() => time.frame(),
+            TimeSource::Manual => time.frame(),
+            _ => {
+                let frame_duration = time.step as f64 / time.scale as f64;
+                (self.shader_time(time) / frame_duration).floor() as i32
+            }
+        }
+    }
+
+    // The layer time showing what the shader sees at `time`, time shifted
+    // layer inputs are checked out relative to it.
+    pub fn retimed(&self, time: FrameTime) -> FrameTime {
+        if self.is_identity() {
+            return time;
+        }
+
+        let layer_seconds = self.shader_time(time) - self.comp_offset;
+
+        FrameTime {
+            time: (layer_seconds * time.scale as f64).round() as i32,
+            ..time
+        }
+    }
+}
+
+// Comp time minus layer time, in seconds
+fn comp_time_offset(in_data: InData, time: FrameTime) -> Result<f64, Error> {
+    Ok(seconds(comp_time(in_data, time)?) - time.time as f64 / time.scale as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 30 fps
+    fn at_seconds(seconds: f64) -> FrameTime {
+        FrameTime {
+            time: (seconds * 30.0).round() as i32,
+            step: 1,
+            scale: 30,
+        }
+    }
+
+    fn controls(source: TimeSource, offset: f64, speed: f64, loop_duration: f64) -> TimeControls {
+        TimeControls {
+            source,
+            offset,
+            speed,
+            loop_duration,
+            manual: 0.0,
+            comp_offset: 0.0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn identity_passes_layer_time_through() {
+        let identity = controls(TimeSource::Layer, 0.0, 1.0, 0.0);
+        let time = at_seconds(2.5);
+
+        assert!(identity.is_identity());
+        assert_close(identity.shader_time(time), 2.5);
+        assert_eq!(identity.shader_frame(time), time.frame());
+        assert_eq!(identity.retimed(time), time);
+    }
+
+    #[test]
+    fn offset_applies_after_speed() {
+        let fast = controls(TimeSource::Layer, 1.0, 2.0, 0.0);
+
+        assert_close(fast.shader_time(at_seconds(3.0)), 7.0);
+        assert_eq!(fast.shader_frame(at_seconds(3.0)), 210);
+        assert_eq!(fast.retimed(at_seconds(3.0)), at_seconds(7.0));
+    }
+
+    #[test]
+    fn loops_wrap_forwards_and_backwards() {
+        let looped = controls(TimeSource::Layer, 0.0, 1.0, 2.0);
+        assert_close(looped.shader_time(at_seconds(5.0)), 1.0);
+        assert_eq!(looped.retimed(at_seconds(5.0)), at_seconds(1.0));
+
+        let reversed = controls(TimeSource::Layer, 0.0, -1.0, 2.0);
+        assert_close(reversed.shader_time(at_seconds(0.5)), 1.5);
+        assert_eq!(reversed.shader_frame(at_seconds(0.5)), 45);
+    }
+
+    #[test]
+    fn negative_speed_without_a_loop_runs_backwards() {
+        let reversed = controls(TimeSource::Layer, 10.0, -1.0, 0.0);
+
+        assert_close(reversed.shader_time(at_seconds(4.0)), 6.0);
+        assert_eq!(reversed.retimed(at_seconds(4.0)), at_seconds(6.0));
+    }
+
+    #[test]
+    fn comp_time_retimes_back_to_layer_time() {
+        let comp = TimeControls {
+            comp_offset: 10.0,
+            ..controls(TimeSource::Comp, 0.0, 1.0, 0.0)
+        };
+
+        assert_close(comp.shader_time(at_seconds(3.0)), 13.0);
+        assert_eq!(comp.retimed(at_seconds(3.0)), at_seconds(3.0));
+    }
+
+    #[test]
+    fn manual_time_ignores_the_clock() {
+        let manual = TimeControls {
+            manual: 4.0,
+            ..controls(TimeSource::Manual, 1.0, 2.0, 3.0)
+        };
+
+        assert_close(manual.shader_time(at_seconds(9.0)), 4.0);
+        assert_eq!(manual.shader_frame(at_seconds(9.0)), 270);
+        assert_close(manual.shader_delta(at_seconds(9.0)), 0.0);
+    }
+
+    #[test]
+    fn delta_is_a_frame_of_shader_time() {
+        let fast = controls(TimeSource::Layer, 0.0, 2.0, 0.0);
+        assert_close(fast.shader_delta(at_seconds(1.0)), 2.0 / 30.0);
+
+        let reversed = controls(TimeSource::Layer, 0.0, -1.0, 0.0);
+        assert_close(reversed.shader_delta(at_seconds(1.0)), -1.0 / 30.0);
+
+        // the frame a loop wraps on is still one frame after the last
+        let looped = controls(TimeSource::Layer, 0.0, 1.0, 1.0);
+        assert_close(looped.shader_delta(at_seconds(1.0)), 1.0 / 30.0);
+    }
+
+    #[test]
+    fn delta_follows_sub_frames() {
+        let normal = controls(TimeSource::Layer, 0.0, 1.0, 0.0);
+        let sub_frame = at_seconds(1.0).offset_frames(0.25);
+
+        assert_close(normal.shader_delta(sub_frame), 1.0 / 30.0);
+        assert_close(normal.shader_time(sub_frame), 1.0 + 0.25 / 30.0);
+    }
+}
@@ -1,9 +1,19 @@
-use crate::{preprocessing, u15_conversion::*, window_handle::WindowAndDisplayHandle};
+use crate::{
+    annotations,
+    audio::{AudioCache, AudioKind},
+    host_uniforms,
+    motion_blur::MotionBlurContext,
+    param_util, preprocessing,
+    simulation::SimulationCache,
+    supersample::SupersampleContext,
+    u15_conversion::*,
+    window_handle::WindowAndDisplayHandle,
+};
 use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, sync::Mutex};
 use tweak_shader::wgpu::{self, Device, Queue};
 
-#[repr(u8)]
+#[repr(u16)]
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Hash)]
 pub enum ParamIdx {
     LoadButton = 1,
@@ -12,7 +22,21 @@ pub enum ParamIdx {
     Time = 4,
     IsImageFilter = 5,
     UseLayerTime = 6,
-    Dynamic(u8),
+    ExpandPixels = 231,
+    MotionBlurSamples = 232,
+    ShutterAngle = 233,
+    UseCompShutter = 234,
+    Supersampling = 235,
+    SupersampleFilter = 236,
+    DateSource = 237,
+    StartDate = 238,
+    Mouse = 239,
+    MouseDown = 240,
+    TimeSource = 241,
+    TimeOffset = 242,
+    PlaybackSpeed = 243,
+    LoopDuration = 244,
+    Dynamic(u16),
 }
 
 impl std::cmp::Eq for ParamIdx {}
@@ -26,13 +50,27 @@ impl ParamIdx {
             ParamIdx::Time => 4,
             ParamIdx::IsImageFilter => 5,
             ParamIdx::UseLayerTime => 6,
+            ParamIdx::ExpandPixels => 231,
+            ParamIdx::MotionBlurSamples => 232,
+            ParamIdx::ShutterAngle => 233,
+            ParamIdx::UseCompShutter => 234,
+            ParamIdx::Supersampling => 235,
+            ParamIdx::SupersampleFilter => 236,
+            ParamIdx::DateSource => 237,
+            ParamIdx::StartDate => 238,
+            ParamIdx::Mouse => 239,
+            ParamIdx::MouseDown => 240,
+            ParamIdx::TimeSource => 241,
+            ParamIdx::TimeOffset => 242,
+            ParamIdx::PlaybackSpeed => 243,
+            ParamIdx::LoopDuration => 244,
             ParamIdx::Dynamic(x) => *x as i32,
         }
     }
 }
 
-impl From<u8> for ParamIdx {
-    fn from(value: u8) -> Self {
+impl From<u16> for ParamIdx {
+    fn from(value: u16) -> Self {
         match value {
             1 => ParamIdx::LoadButton,
             2 => ParamIdx::UnloadButton,
@@ -40,12 +78,26 @@ impl From<u8> for ParamIdx {
             4 => ParamIdx::Time,
             5 => ParamIdx::IsImageFilter,
             6 => ParamIdx::UseLayerTime,
+            231 => ParamIdx::ExpandPixels,
+            232 => ParamIdx::MotionBlurSamples,
+            233 => ParamIdx::ShutterAngle,
+            234 => ParamIdx::UseCompShutter,
+            235 => ParamIdx::Supersampling,
+            236 => ParamIdx::SupersampleFilter,
+            237 => ParamIdx::DateSource,
+            238 => ParamIdx::StartDate,
+            239 => ParamIdx::Mouse,
+            240 => ParamIdx::MouseDown,
+            241 => ParamIdx::TimeSource,
+            242 => ParamIdx::TimeOffset,
+            243 => ParamIdx::PlaybackSpeed,
+            244 => ParamIdx::LoopDuration,
             _ => ParamIdx::Dynamic(value),
         }
     }
 }
 
-impl From<ParamIdx> for u8 {
+impl From<ParamIdx> for u16 {
     fn from(value: ParamIdx) -> Self {
         match value {
             ParamIdx::LoadButton => 1,
@@ -54,6 +106,20 @@ impl From<ParamIdx> for u8 {
             ParamIdx::Time => 4,
             ParamIdx::IsImageFilter => 5,
             ParamIdx::UseLayerTime => 6,
+            ParamIdx::ExpandPixels => 231,
+            ParamIdx::MotionBlurSamples => 232,
+            ParamIdx::ShutterAngle => 233,
+            ParamIdx::UseCompShutter => 234,
+            ParamIdx::Supersampling => 235,
+            ParamIdx::SupersampleFilter => 236,
+            ParamIdx::DateSource => 237,
+            ParamIdx::StartDate => 238,
+            ParamIdx::Mouse => 239,
+            ParamIdx::MouseDown => 240,
+            ParamIdx::TimeSource => 241,
+            ParamIdx::TimeOffset => 242,
+            ParamIdx::PlaybackSpeed => 243,
+            ParamIdx::LoopDuration => 244,
             ParamIdx::Dynamic(x) => x,
         }
     }
@@ -147,6 +213,18 @@ pub struct InnerGlobal {
 
 pub type LocalMutex = Mutex<Local>;
 
+// How inputs map to the params backing them, see `param_util::input_slots`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParamLayout {
+    // every input owns one param of each type, for projects
+    // saved before the pools were added.
+    Legacy,
+    // inputs of different types share the groups of params,
+    // so each type draws from a pool of its own
+    #[default]
+    Pooled,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Local {
     // Post initialization only fields
@@ -156,6 +234,8 @@ pub struct Local {
     pub src: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub src_path: Option<PathBuf>,
+    #[serde(default)]
+    pub param_layout: ParamLayout,
 }
 
 #[derive(Debug)]
@@ -166,6 +246,130 @@ pub struct LocalInit {
     pub fmt: wgpu::TextureFormat,
     pub build_error: Option<String>,
     pub u16_converter: Option<U16ConversionContext>,
+    pub annotations: annotations::Annotations,
+    pub simulation: SimulationCache,
+    pub audio: AudioCache,
+    // created the first time a frame is motion blurred
+    pub motion_blur: Option<MotionBlurContext>,
+    // created the first time a frame is supersampled
+    pub supersample: Option<SupersampleContext>,
+    // true if the shader can be rendered in tiles, see `offset_frag_coord`
+    pub tileable: bool,
+    // false if frames depend only on params and layer inputs
+    pub time_dependent: bool,
+    pub utility_usage: preprocessing::UtilityUsage,
+    pub param_layout: ParamLayout,
+}
+
+// Computed during smart pre render and handed to smart render
+// through the pre render data.
+#[derive(Debug, Default, Clone)]
+pub struct FrameLayout {
+    // pixels the output was grown by [left, top, right, bottom]
+    pub expansion: [i32; 4],
+    pub inputs: Vec<InputPlacement>,
+    // times to accumulate for motion blur, empty when it is off
+    pub sub_frames: Vec<FrameTime>,
+}
+
+// A point in time in AE units, `time / scale` seconds
+// with frames `step` units apart.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTime {
+    pub time: i32,
+    pub step: i32,
+    pub scale: u32,
+}
+
+impl FrameTime {
+    pub fn current(in_data: &after_effects::InData) -> Self {
+        Self {
+            time: in_data.current_time(),
+            step: in_data.time_step(),
+            scale: in_data.time_scale(),
+        }
+    }
+
+    pub fn at_frame(self, frame: i32) -> Self {
+        Self {
+            time: frame * self.step,
+            ..self
+        }
+    }
+
+    pub fn seconds(&self) -> f32 {
+        self.time as f32 / self.scale as f32
+    }
+
+    pub fn frame(&self) -> i32 {
+        self.time.div_euclid(self.step.max(1))
+    }
+
+    // `frames` away from this time, which may fall between frames. The
+    // scale is subdivided when a frame is too few units long to land on it.
+    pub fn offset_frames(self, frames: f32) -> Self {
+        const SUBDIVISION: i64 = 64;
+
+        let subdivided = (
+            self.time as i64 * SUBDIVISION,
+            self.step as i64 * SUBDIVISION,
+            self.scale as i64 * SUBDIVISION,
+        );
+
+        let (time, step, scale) = match subdivided {
+            (time, step, scale) if scale <= u32::MAX as i64 && time.abs() < i32::MAX as i64 / 2 => {
+                (time, step, scale)
+            }
+            _ => (self.time as i64, self.step as i64, self.scale as i64),
+        };
+
+        Self {
+            time: (time as f64 + frames as f64 * step as f64).round() as i32,
+            step: step as i32,
+            scale: scale as u32,
+        }
+    }
+}
+
+impl FrameLayout {
+    pub fn placement(&self, checkout_id: i32) -> Option<&InputPlacement> {
+        self.inputs.iter().find(|p| p.checkout_id == checkout_id)
+    }
+}
+
+// Where a checked out layer's pixels sit relative to the output buffer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InputPlacement {
+    pub checkout_id: i32,
+    // offset of the layer's top left from the output's top left, in pixels
+    pub origin: [i32; 2],
+    pub size: [i32; 2],
+    pub pixel_aspect: f32,
+    // seconds the layer was checked out at, relative to the current time
+    pub time_offset: f32,
+}
+
+impl InputPlacement {
+    // `rect` is in layer space until made `relative_to` the output
+    pub fn new(
+        checkout_id: i32,
+        rect: after_effects::Rect,
+        pixel_aspect: f32,
+        time_offset: f32,
+    ) -> Self {
+        Self {
+            checkout_id,
+            origin: [rect.left, rect.top],
+            size: [rect.right - rect.left, rect.bottom - rect.top],
+            pixel_aspect,
+            time_offset,
+        }
+    }
+
+    pub fn relative_to(mut self, output: after_effects::Rect) -> Self {
+        self.origin = [self.origin[0] - output.left, self.origin[1] - output.top];
+        self
+    }
 }
 
 impl Default for TweakShaderGlobal {
@@ -249,18 +453,54 @@ impl Default for TweakShaderGlobal {
 }
 
 impl LocalInit {
-    fn new(device: &Device, queue: &Queue, fmt: wgpu::TextureFormat, src: Option<String>) -> Self {
+    fn new(
+        device: &Device,
+        queue: &Queue,
+        fmt: wgpu::TextureFormat,
+        src: Option<String>,
+        param_layout: ParamLayout,
+    ) -> Self {
         let mut build_error = None;
 
         let ctx = src
             .ok_or("No Source in initialization".to_owned())
-            .and_then(|src| preprocessing::convert_output_to_ae_format(&src))
-            .and_then(|src| {
+            .and_then(|src| annotations::extract(&src))
+            .and_then(|(src, annotations)| {
+                let utility_usage = preprocessing::utility_usage(&src);
+                let time_dependent = annotations.time_dependent.unwrap_or_else(|| {
+                    preprocessing::reads_time(&src)
+                        || annotations.simulation.is_some()
+                        || !annotations.persistent_targets.is_empty()
+                });
+                // passes render at the full resolution, they can not be split
+                let tiled_src = if annotations.has_passes {
+                    None
+                } else {
+                    preprocessing::offset_frag_coord(&src)?
+                };
+                let tileable = tiled_src.is_some();
+                let src = preprocessing::convert_output_to_ae_format(
+                    tiled_src.as_deref().unwrap_or(&src),
+                )?;
                 tweak_shader::RenderContext::new(src, fmt, device, queue)
+                    .map(|ctx| {
+                        // AE does not invalidate effects when their layer moves,
+                        // and audio changes every frame.
+                        let time_dependent = time_dependent
+                            || (annotations.time_dependent.is_none()
+                                && (host_uniforms::is_declared(&ctx, host_uniforms::HOST_CONTEXT)
+                                    || ctx
+                                        .iter_inputs()
+                                        .any(|(_, ty)| AudioKind::of(ty).is_some())));
+                        (ctx, annotations, time_dependent, tileable, utility_usage)
+                    })
                     .map_err(|e| format!("{e}"))
+            })
+            .and_then(|loaded| {
+                param_util::check_inputs(&loaded.0, &loaded.1, param_layout).map(|_| loaded)
             });
 
-        let ctx = match ctx {
+        let (ctx, annotations, time_dependent, tileable, utility_usage) = match ctx {
             Ok(okay) => okay,
             Err(e) => {
                 let error_shader = preprocessing::convert_output_to_ae_format(include_str!(
@@ -269,7 +509,15 @@ impl LocalInit {
                 .unwrap();
 
                 build_error = Some(e.to_string());
-                tweak_shader::RenderContext::new(&error_shader, fmt, device, queue).unwrap()
+                let ctx =
+                    tweak_shader::RenderContext::new(&error_shader, fmt, device, queue).unwrap();
+                (
+                    ctx,
+                    annotations::Annotations::default(),
+                    true,
+                    false,
+                    Default::default(),
+                )
             }
         };
 
@@ -285,6 +533,15 @@ impl LocalInit {
             needs_param_setup: true,
             build_error,
             u16_converter,
+            annotations,
+            simulation: SimulationCache::default(),
+            audio: AudioCache::default(),
+            motion_blur: None,
+            supersample: None,
+            tileable,
+            time_dependent,
+            utility_usage,
+            param_layout,
         }
     }
 
@@ -314,6 +571,7 @@ impl Local {
                     queue,
                     expected_fmt,
                     self.src.clone(),
+                    self.param_layout,
                 ));
             }
             Some(LocalInit { fmt, .. }) => {
@@ -324,6 +582,7 @@ impl Local {
                             queue,
                             expected_fmt,
                             self.src.clone(),
+                            self.param_layout,
                         ));
                     }
                 }
@@ -372,7 +631,13 @@ impl Local {
             .map(|l| l.fmt)
             .unwrap_or(wgpu::TextureFormat::Rgba8Unorm);
 
-        let mut local_init = LocalInit::new(device, queue, current_fmt, source.clone());
+        let mut local_init = LocalInit::new(
+            device,
+            queue,
+            current_fmt,
+            source.clone(),
+            self.param_layout,
+        );
         local_init.needs_param_setup = true;
         let out = local_init.build_error.clone();
 
@@ -381,6 +646,32 @@ impl Local {
         out
     }
 
+    // Whether frames depend on more than params and layer inputs, reported
+    // to AE through the NonParamVary flag so static shaders can be cached.
+    pub fn is_time_dependent(&self) -> bool {
+        self.local_init
+            .as_ref()
+            .map(|init| init.time_dependent)
+            .unwrap_or(true)
+    }
+
+    // Whether any layer is checked out away from the current time
+    pub fn reads_other_times(&self) -> bool {
+        self.local_init
+            .as_ref()
+            .map(|init| init.annotations.reads_other_times())
+            .unwrap_or(true)
+    }
+
+    // Whether the shader declared a host uniform, AE only invalidates
+    // renders on camera and light changes for effects that ask.
+    pub fn declares(&self, name: &str) -> bool {
+        self.local_init
+            .as_ref()
+            .map(|init| host_uniforms::is_declared(&init.ctx, name))
+            .unwrap_or(false)
+    }
+
     pub fn unload_scene(&mut self) {
         self.src = None;
         self.local_init = None;
@@ -410,7 +701,13 @@ impl Local {
             .map(|l| l.fmt)
             .unwrap_or(wgpu::TextureFormat::Rgba8Unorm);
 
-        let mut local_init = LocalInit::new(device, queue, current_fmt, source.clone());
+        let mut local_init = LocalInit::new(
+            device,
+            queue,
+            current_fmt,
+            source.clone(),
+            self.param_layout,
+        );
         local_init.needs_param_setup = true;
         let out = local_init.build_error.clone();
 
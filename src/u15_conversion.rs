@@ -1,3 +1,4 @@
+use crate::texture_util::target_desc;
 use after_effects as ae;
 use tweak_shader::{
     wgpu::{self, Device, Queue},
@@ -51,9 +52,7 @@ impl U16ConversionContext {
         queue: &Queue,
         main_render_ctx: &mut tweak_shader::RenderContext,
     ) {
-        for (name, tex) in self.fp_staging_textures.iter() {
-            main_render_ctx.load_shared_texture(tex, name);
-        }
+        self.bind_staging_textures(main_render_ctx);
 
         let width = out_layer.width() as u32;
         let height = out_layer.height() as u32;
@@ -100,6 +99,40 @@ impl U16ConversionContext {
         );
     }
 
+    // Converts an already rendered floating point texture into the output layer
+    pub fn render_texture_to_cpu_buffer(
+        &mut self,
+        out_layer: &mut ae::Layer,
+        device: &Device,
+        queue: &Queue,
+        texture: &wgpu::Texture,
+    ) {
+        self.fp_to_u16_ctx
+            .load_shared_texture(texture, "input_image");
+
+        let stride = out_layer.buffer_stride();
+        self.fp_to_u16_ctx.render_to_slice(
+            queue,
+            device,
+            texture.width(),
+            texture.height(),
+            out_layer.buffer_mut(),
+            Some(stride as u32),
+        );
+    }
+
+    // Binds the converted layer inputs to the main shader
+    pub fn bind_staging_textures(&self, main_render_ctx: &mut tweak_shader::RenderContext) {
+        for (name, tex) in self.fp_staging_textures.iter() {
+            main_render_ctx.load_shared_texture(tex, name);
+        }
+    }
+
+    // Drops staging textures for inputs that are no longer fed by a layer
+    pub fn retain_staging_textures(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.fp_staging_textures.retain(|name, _| keep(name));
+    }
+
     // Loads or creates all textures from the iterator into staging buffers.
     // converts from u15 to floating point 32
     pub fn prepare_cpu_layer_inputs<'a, I>(&mut self, device: &Device, queue: &Queue, layers: I)
@@ -152,28 +185,3 @@ impl U16ConversionContext {
         queue.submit([render_encoder.finish()]);
     }
 }
-
-fn target_desc(
-    width: u32,
-    height: u32,
-    format: wgpu::TextureFormat,
-) -> wgpu::TextureDescriptor<'static> {
-    wgpu::TextureDescriptor {
-        label: None,
-        size: wgpu::Extent3d {
-            width,
-            height,
-            depth_or_array_layers: 1,
-        },
-        mip_level_count: 1,
-        sample_count: 1, // crunch crunch
-        dimension: wgpu::TextureDimension::D2,
-        format,
-        usage: wgpu::TextureUsages::COPY_DST
-            | wgpu::TextureUsages::TEXTURE_BINDING
-            | wgpu::TextureUsages::RENDER_ATTACHMENT
-            | wgpu::TextureUsages::STORAGE_BINDING
-            | wgpu::TextureUsages::COPY_SRC,
-        view_formats: &[],
-    }
-}